@@ -3,4 +3,18 @@ pub enum Error {
   ParseError(String),
   BoundsError(String),
   ConversionError(String),
+  IoError(String),
 }
+
+impl std::fmt::Display for Error {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Error::ParseError(s) => write!(f, "Parse error: {}", s),
+      Error::BoundsError(s) => write!(f, "Bounds error: {}", s),
+      Error::ConversionError(s) => write!(f, "Conversion error: {}", s),
+      Error::IoError(s) => write!(f, "I/O error: {}", s),
+    }
+  }
+}
+
+impl std::error::Error for Error {}
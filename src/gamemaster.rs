@@ -1,5 +1,11 @@
-use serde;
-use serde::Deserialize;
+use crate::error::Error;
+
+use serde::de::{self, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
 
 #[derive(Deserialize, Debug)]
 pub struct AvatarCustomization {
@@ -24,19 +30,118 @@ pub struct PvPMove {
   // Sort of "how many turns do I have to waste in excess of 1"
   pub duration_turns: i32,
   pub energy_delta: i32,
+  pub buffs: Option<PvPMoveBuffs>,
+}
+
+// Absent stage changes are zero
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct PvPMoveBuffs {
+  pub attacker_attack_stat_stage_change: i32,
+  pub attacker_defense_stat_stage_change: i32,
+  pub target_attack_stat_stage_change: i32,
+  pub target_defense_stat_stage_change: i32,
+  pub buff_activation_chance: f64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CombatSettings {
+  pub same_type_attack_bonus_multiplier: f64,
+  pub fast_attack_bonus_multiplier: f64,
+  pub charge_attack_bonus_multiplier: f64,
+  pub defense_bonus_multiplier: Option<f64>,
+  pub max_energy: i32,
+  pub turn_duration_seconds: Option<f64>,
+  pub round_duration_seconds: Option<f64>,
+  pub change_pokemon_duration_seconds: Option<f64>,
+  pub quick_swap_cooldown_duration_seconds: Option<f64>,
+  pub shadow_pokemon_attack_bonus_multiplier: Option<f64>,
+  pub shadow_pokemon_defense_bonus_multiplier: Option<f64>,
+  pub purified_pokemon_attack_multiplier_vs_shadow: Option<f64>,
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
-struct FormDetail {
-  form: String,
-  asset_bundle_suffix: Option<String>,
+pub struct PokemonCpLimit {
+  pub min_cp: Option<u32>,
+  pub max_cp: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PokemonTypeList {
+  pub pokemon_type: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PokemonListEntry {
+  pub id: String,
+  pub form: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PokemonList {
+  pub pokemon: Vec<PokemonListEntry>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PokemonLevelRange {
+  pub min_level: Option<u16>,
+  pub max_level: Option<u16>,
+}
+
+// Only one of the payloads is set, according to `type_`
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PokemonCondition {
+  #[serde(rename = "type")]
+  pub type_: String,
+  pub with_pokemon_cp_limit: Option<PokemonCpLimit>,
+  pub with_pokemon_type: Option<PokemonTypeList>,
+  pub pokemon_white_list: Option<PokemonList>,
+  pub pokemon_ban_list: Option<PokemonList>,
+  pub pokemon_level_range: Option<PokemonLevelRange>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CombatLeague {
+  pub title: String,
+  #[serde(default)]
+  pub enabled: bool,
+  #[serde(default)]
+  pub pokemon_condition: Vec<PokemonCondition>,
+  pub pokemon_count: Option<u32>,
+  #[serde(default)]
+  pub banned_pokemon: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CombatStatStageSettings {
+  pub minimum_stat_stage: i32,
+  pub maximum_stat_stage: i32,
+  pub attack_buff_multiplier: Vec<f64>,
+  pub defense_buff_multiplier: Vec<f64>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FormDetail {
+  pub form: String,
+  pub asset_bundle_suffix: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct Form {
-  pokemon: String,
-  forms: Vec<FormDetail>,
+  pub pokemon: String,
+  // Absent for species that have a single form
+  #[serde(default)]
+  pub forms: Vec<FormDetail>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -64,43 +169,76 @@ pub struct Stats {
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct ThirdMove {
-  stardust_to_unlock: u64,
-  candy_to_unlock: u64,
+  pub stardust_to_unlock: u64,
+  pub candy_to_unlock: u64,
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct PokemonSettings {
   pub pokemon_id: String,
-  family_id: String,
+  pub family_id: String,
   #[serde(rename = "type")]
   pub type1: String,
   pub type2: Option<String>,
   pub stats: Stats,
   pub quick_moves: Vec<String>,
   pub cinematic_moves: Vec<String>,
-  third_move: ThirdMove,
-  candy_to_evolve: Option<u64>,
+  pub third_move: ThirdMove,
+  pub candy_to_evolve: Option<u64>,
   pub form: Option<String>,
+  pub shadow: Option<ShadowSettings>,
+  pub parent_pokemon_id: Option<String>,
+  #[serde(default)]
+  pub evolution_branch: Vec<EvolutionBranch>,
+}
+
+// Mega evolutions are branches without an `evolution`
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct EvolutionBranch {
+  pub evolution: Option<String>,
+  pub candy_cost: Option<u64>,
+  pub form: Option<String>,
+  pub evolution_item_requirement: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ShadowSettings {
+  pub purification_stardust_needed: u64,
+  pub purification_candy_needed: u64,
+  pub purified_charge_move: String,
+  pub shadow_charge_move: String,
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct PokemonUpgrades {
-  candy_cost: Vec<u16>,
-  stardust_cost: Vec<u16>,
-  shadow_stardust_multiplier: f64,
-  shadow_candy_multiplier: f64,
-  purified_stardust_multiplier: f64,
-  purified_candy_multiplier: f64,
+  pub candy_cost: Vec<u16>,
+  pub stardust_cost: Vec<u16>,
+  pub shadow_stardust_multiplier: f64,
+  pub shadow_candy_multiplier: f64,
+  pub purified_stardust_multiplier: f64,
+  pub purified_candy_multiplier: f64,
+  // Only in GameMasters with XL candy, for levels from max_normal_upgrade_level
+  pub max_normal_upgrade_level: Option<u16>,
+  #[serde(default)]
+  pub xl_candy_cost: Vec<u16>,
 }
 
 #[derive(Deserialize, Debug)]
 pub enum GameMasterEntry {
   #[serde(rename = "avatarCustomization")]
   AvatarCustomization(AvatarCustomization),
+  #[serde(rename = "combatLeague")]
+  CombatLeague(CombatLeague),
   #[serde(rename = "combatMove")]
   PvPMove(PvPMove),
+  #[serde(rename = "combatSettings")]
+  CombatSettings(CombatSettings),
+  #[serde(rename = "combatStatStageSettings")]
+  CombatStatStageSettings(CombatStatStageSettings),
   #[serde(rename = "formSettings")]
   Form(Form),
   #[serde(rename = "playerLevel")]
@@ -116,39 +254,126 @@ pub enum GameMasterEntry {
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct ItemTemplate {
-  template_id: String,
+  // Newer dumps repeat it inside `data`, but it's only guaranteed outside
+  #[serde(default)]
+  pub template_id: String,
   #[serde(flatten)]
   pub entry: Option<GameMasterEntry>,
 }
 
+// Newer dumps: `[{ "templateId": ..., "data": { "templateId": ..., <entry> } }]`
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
+struct TemplateWithData {
+  template_id: String,
+  data: ItemTemplate,
+}
+
+impl From<TemplateWithData> for ItemTemplate {
+  fn from(t: TemplateWithData) -> ItemTemplate {
+    ItemTemplate {
+      template_id: t.template_id,
+      entry: t.data.entry,
+    }
+  }
+}
+
+/// Both the legacy `{"itemTemplates": [...]}` layout and the newer top-level
+/// array of `{templateId, data}` objects deserialize into this.
+#[derive(Debug)]
 pub struct GameMaster {
   pub item_templates: Vec<ItemTemplate>,
 }
 
+// Dispatching on the first token instead of using `#[serde(untagged)]` keeps
+// the underlying error (e.g. which field is malformed) in the message.
+struct GameMasterVisitor;
+
+impl<'de> Visitor<'de> for GameMasterVisitor {
+  type Value = GameMaster;
+
+  fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "an object with itemTemplates or an array of templates")
+  }
+
+  fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<GameMaster, A::Error> {
+    let mut item_templates = None;
+    while let Some(key) = map.next_key::<String>()? {
+      if key == "itemTemplates" {
+        item_templates = Some(map.next_value::<Vec<ItemTemplate>>()?);
+      } else {
+        map.next_value::<IgnoredAny>()?;
+      }
+    }
+    Ok(GameMaster {
+      item_templates: item_templates.ok_or_else(|| de::Error::missing_field("itemTemplates"))?,
+    })
+  }
+
+  fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<GameMaster, A::Error> {
+    let mut item_templates = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+    while let Some(t) = seq.next_element::<TemplateWithData>()? {
+      item_templates.push(ItemTemplate::from(t));
+    }
+    Ok(GameMaster { item_templates })
+  }
+}
+
+impl<'de> Deserialize<'de> for GameMaster {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<GameMaster, D::Error> {
+    deserializer.deserialize_any(GameMasterVisitor)
+  }
+}
+
 lazy_static! {
-  static ref GAMEMASTER: GameMaster = {
+  static ref GAMEMASTER: GameMaster = GameMaster::from_env()
+    .unwrap_or_else(|e| panic!("Couldn't load the default GameMaster: {}", e));
+}
+
+impl GameMaster {
+  /// Loads the GameMaster from the JSON file at `path`.
+  pub fn load<P: AsRef<Path>>(path: P) -> Result<GameMaster, Error> {
+    let path = path.as_ref();
+    let file = File::open(path).map_err(|e| {
+      Error::IoError(format!("Couldn't open GameMaster {}: {}", path.display(), e))
+    })?;
+    GameMaster::from_reader(BufReader::new(file))
+  }
+
+  /// Parses the GameMaster from any JSON source.
+  pub fn from_reader<R: Read>(reader: R) -> Result<GameMaster, Error> {
+    serde_json::from_reader(reader)
+      .map_err(|e| Error::ParseError(format!("Couldn't parse GameMaster: {}", e)))
+  }
+
+  /// Loads the GameMaster from `PVPTIMIZE_GM_PATH` (also read from `.env`),
+  /// falling back to `data/gamemaster.json`.
+  pub fn from_env() -> Result<GameMaster, Error> {
     dotenv::dotenv().ok();
     let gm_path = std::env::var("PVPTIMIZE_GM_PATH")
       .unwrap_or_else(|_| String::from("data/gamemaster.json"));
-    let gms = std::fs::read_to_string(gm_path).unwrap();
-    let gm = serde_json::from_str::<GameMaster>(&gms).unwrap();
-    gm
-  };
-}
+    GameMaster::load(gm_path)
+  }
 
-impl GameMaster {
+  /// Process-wide GameMaster loaded through `from_env`. This is a convenience
+  /// for tests and scripts: it panics if the file is missing or malformed.
   pub fn instance() -> &'static GameMaster {
     &GAMEMASTER
   }
 }
 
+// Hand-written subset of a real game master, for tests that can't rely on
+// data/gamemaster.json being present
+#[cfg(test)]
+pub(crate) fn test_fixture() -> GameMaster {
+  GameMaster::from_reader(include_str!("../tests/fixtures/gamemaster.json").as_bytes()).unwrap()
+}
+
 #[cfg(test)]
 mod test {
 
   use super::*;
-  use crate::model::{Mechanics, Type, TYPE_ORDERING};
+  use crate::model::{Mechanics, Type};
   use std::convert::TryFrom;
 
   #[test]
@@ -157,15 +382,163 @@ mod test {
     // let gm = serde_json::from_str::<GameMaster>(&gms).unwrap();
     // let mech = Mechanics::try_from(gm).unwrap();
     let mech = Mechanics::instance();
-    let steel_psychic = mech.defender_dual_type_effectiveness(Type::Steel, Type::Psychic);
+    let steel_psychic = mech.type_effectiveness.defend(&[Type::Steel, Type::Psychic]);
+
+    assert!((steel_psychic[Type::Poison] - 0.391).abs() < 10e-3);
+    assert!((steel_psychic[Type::Psychic] - 0.391).abs() < 10e-3);
+    assert!((steel_psychic[Type::Ghost] - 1.6).abs() < 10e-3);
+    assert!((steel_psychic[Type::Fighting] - 1.).abs() < 10e-3);
+
+    for (k, m) in steel_psychic.iter() {
+      println!("{:>15} {:.3}", format!("{:?}", k), m);
+    }
+  }
+
+  #[test]
+  fn test_load_errors() {
+    match GameMaster::load("data/does-not-exist.json") {
+      Err(Error::IoError(_)) => {}
+      r => panic!("Expected IoError, got {:?}", r),
+    }
+
+    match GameMaster::from_reader(r#"{"itemTemplates": ["#.as_bytes()) {
+      Err(Error::ParseError(_)) => {}
+      r => panic!("Expected ParseError, got {:?}", r),
+    }
+
+    match GameMaster::from_reader(r#"{"templates": []}"#.as_bytes()) {
+      Err(Error::ParseError(e)) => assert!(e.contains("itemTemplates")),
+      r => panic!("Expected ParseError, got {:?}", r),
+    }
+  }
+
+  #[test]
+  fn test_from_reader() {
+    let gm = GameMaster::from_reader(
+      r#"{"itemTemplates": [
+        { "templateId": "PLAYER_LEVEL_SETTINGS", "playerLevel": { "cpMultiplier": [0.094, 0.16639787] } },
+        { "templateId": "UNKNOWN_TEMPLATE", "somethingElse": {} }
+      ]}"#.as_bytes(),
+    )
+    .unwrap();
+
+    assert_eq!(gm.item_templates.len(), 2);
+    assert!(gm.item_templates[1].entry.is_none());
+
+    // Too few CPM entries and no type chart: Mechanics must refuse it, not panic
+    assert!(Mechanics::from_gamemaster(&gm).is_err());
+  }
+
+  #[test]
+  fn test_layouts() {
+    let item_templates = GameMaster::from_reader(
+      r#"{"itemTemplates": [
+        {
+          "templateId": "COMBAT_V0250_MOVE_DRAGON_BREATH_FAST",
+          "combatMove": {
+            "uniqueId": "DRAGON_BREATH_FAST",
+            "type": "POKEMON_TYPE_DRAGON",
+            "power": 4.0,
+            "vfxName": "dragon_breath_fast",
+            "energyDelta": 3
+          }
+        },
+        { "templateId": "UNKNOWN_TEMPLATE", "somethingElse": {} }
+      ]}"#
+        .as_bytes(),
+    )
+    .unwrap();
+
+    let templates_with_data = GameMaster::from_reader(
+      r#"[
+        {
+          "templateId": "COMBAT_V0250_MOVE_DRAGON_BREATH_FAST",
+          "data": {
+            "templateId": "COMBAT_V0250_MOVE_DRAGON_BREATH_FAST",
+            "combatMove": {
+              "uniqueId": "DRAGON_BREATH_FAST",
+              "type": "POKEMON_TYPE_DRAGON",
+              "power": 4.0,
+              "vfxName": "dragon_breath_fast",
+              "energyDelta": 3
+            }
+          }
+        },
+        { "templateId": "UNKNOWN_TEMPLATE", "data": { "somethingElse": {} } }
+      ]"#
+        .as_bytes(),
+    )
+    .unwrap();
 
-    assert!((steel_psychic[&Type::Poison] - 0.391).abs() < 10e-3);
-    assert!((steel_psychic[&Type::Psychic] - 0.391).abs() < 10e-3);
-    assert!((steel_psychic[&Type::Ghost] - 1.6).abs() < 10e-3);
-    assert!((steel_psychic[&Type::Fighting] - 1.).abs() < 10e-3);
+    for gm in &[item_templates, templates_with_data] {
+      assert_eq!(gm.item_templates.len(), 2);
+      assert_eq!(gm.item_templates[0].template_id, "COMBAT_V0250_MOVE_DRAGON_BREATH_FAST");
+      match &gm.item_templates[0].entry {
+        Some(GameMasterEntry::PvPMove(m)) => {
+          assert_eq!(m.unique_id, "DRAGON_BREATH_FAST");
+          assert_eq!(m.duration_turns, 0);
+          assert_eq!(m.energy_delta, 3);
+        }
+        e => panic!("Expected a PvPMove, got {:?}", e),
+      }
+      assert_eq!(gm.item_templates[1].template_id, "UNKNOWN_TEMPLATE");
+      assert!(gm.item_templates[1].entry.is_none());
+    }
+  }
+
+  #[test]
+  fn test_move_buffs() {
+    use crate::model::{ChargedMove, MoveBuffs};
+
+    let gm = GameMaster::from_reader(
+      r#"{"itemTemplates": [
+        {
+          "templateId": "COMBAT_V0245_MOVE_POWER_UP_PUNCH",
+          "combatMove": {
+            "uniqueId": "POWER_UP_PUNCH",
+            "type": "POKEMON_TYPE_FIGHTING",
+            "power": 20.0,
+            "vfxName": "power_up_punch",
+            "energyDelta": -35,
+            "buffs": { "attackerAttackStatStageChange": 1, "buffActivationChance": 1.0 }
+          }
+        },
+        {
+          "templateId": "COMBAT_STAT_STAGE_SETTINGS",
+          "combatStatStageSettings": {
+            "minimumStatStage": -4,
+            "maximumStatStage": 4,
+            "attackBuffMultiplier": [0.5, 0.5714286, 0.6666667, 0.8, 1.0, 1.25, 1.5, 1.75, 2.0],
+            "defenseBuffMultiplier": [0.5, 0.5714286, 0.6666667, 0.8, 1.0, 1.25, 1.5, 1.75, 2.0]
+          }
+        }
+      ]}"#
+        .as_bytes(),
+    )
+    .unwrap();
+
+    match &gm.item_templates[0].entry {
+      Some(GameMasterEntry::PvPMove(m)) => {
+        assert_eq!(
+          ChargedMove::try_from(m).unwrap().buffs,
+          Some(MoveBuffs {
+            attacker_attack: 1,
+            attacker_defense: 0,
+            target_attack: 0,
+            target_defense: 0,
+            chance: 1.0,
+          })
+        );
+      }
+      e => panic!("Expected a PvPMove, got {:?}", e),
+    }
 
-    for k in TYPE_ORDERING.iter() {
-      println!("{:>15} {:.3}", format!("{:?}", k), steel_psychic[k]);
+    match &gm.item_templates[1].entry {
+      Some(GameMasterEntry::CombatStatStageSettings(s)) => {
+        assert_eq!(s.minimum_stat_stage, -4);
+        assert_eq!(s.attack_buff_multiplier.len(), 9);
+      }
+      e => panic!("Expected CombatStatStageSettings, got {:?}", e),
     }
   }
 }
@@ -1,11 +1,12 @@
-use crate::model::PokemonInstance;
-use crate::model::moves::Damage;
+use crate::error::Error;
+use crate::model::{CombatSettings, League, PokemonInstance, StatStageSettings};
+use crate::model::moves::{ChargedMove, Damage, FastMove, MoveBuffs};
 
 pub trait StateMachine<D> {
   fn transition(self: &Self, env: D) -> Self;
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
 pub enum ChargedChoice {
   Main, Other
 }
@@ -14,69 +15,105 @@ pub enum ChargedChoice {
 pub enum MoveStateMachine {
   Neutral,
   Idle(i32),
+  // Chose to do nothing for a turn
+  Wait,
   RegisterFast,
   RegisterCharged(ChargedChoice)
 }
 
-// TODO: consider also the other pokemon
+impl MoveStateMachine {
+  // A fast move idles through all of its turns but the last, on which it
+  // registers
+  fn start_fast(fast_move: &FastMove) -> Self {
+    match fast_move.duration() {
+      d if d > 1 => MoveStateMachine::Idle(d - 2),
+      _ => MoveStateMachine::RegisterFast,
+    }
+  }
+}
+
 impl<'a> StateMachine<(&TurnState<'a>, &TurnState<'a>)> for MoveStateMachine {
   fn transition(&self, env: (&TurnState<'a>, &TurnState<'a>)) -> Self {
     let (attacker, defender) = env;
     match self {
-      MoveStateMachine::Neutral => {
-        // TODO logic to implement in this branch:
-        // x Shield bait logic:
-        //   if energy >= best move energy cost:
-        //     if best move energy cost > other move energy cost:
-        //       if opponent has shields:
-        //         do other move
-        //       else:
-        //         do best move
-        //     else:
-        //       do best move
-        //   else:
-        //     do fast move
-        // - Fast move priority: if fast move kills opponent, choose it first
-        // - would_charged_kill: if worse but less-energy-costly charged move would
-        //   kill the opponent, use it as soon as there's enough energy
-        let dpe_main: f64 =
-          attacker.instance.charged_move1.calculate(attacker.instance, defender.instance) as f64 /
-          -attacker.instance.charged_move1.energy as f64;
-        let dpe_other: f64 =
-          attacker.instance.charged_move2.calculate(attacker.instance, defender.instance) as f64 /
-          -attacker.instance.charged_move2.energy as f64;
-
-        // Compute the best charged move
-        let (best_move, other_move) = if dpe_main > dpe_other {
-          (
-            (&attacker.instance.charged_move1, ChargedChoice::Main),
-            (&attacker.instance.charged_move2, ChargedChoice::Other),
-          )
-        } else {
-          (
-            (&attacker.instance.charged_move2, ChargedChoice::Other),
-            (&attacker.instance.charged_move1, ChargedChoice::Main),
-          )
-        };
-
-        if attacker.state.energy + best_move.0.energy >= 0 {
-          if (best_move.0.energy.abs() >= other_move.0.energy.abs()) && (defender.state.shields != Shields::None) {
-            MoveStateMachine::RegisterCharged(other_move.1)
-          } else {
-            MoveStateMachine::RegisterCharged(best_move.1)
-          }
-        } else {
-          MoveStateMachine::RegisterFast
-        }
+      // Charged moves the Pokémon can't pay for fall back to its fast move
+      MoveStateMachine::Neutral => match attacker.strategy.decide(attacker, defender) {
+        Action::Charged(choice) if attacker.can_throw(choice) => MoveStateMachine::RegisterCharged(choice),
+        Action::Charged(_) | Action::Fast => MoveStateMachine::start_fast(&attacker.instance.fast_move),
+        Action::Wait => MoveStateMachine::Wait,
       },
       MoveStateMachine::Idle(0) => MoveStateMachine::RegisterFast,
       MoveStateMachine::Idle(i) => MoveStateMachine::Idle(i - 1),
+      MoveStateMachine::Wait => MoveStateMachine::Neutral.transition(env),
       MoveStateMachine::RegisterFast => MoveStateMachine::Neutral.transition(env),
       MoveStateMachine::RegisterCharged(_) => MoveStateMachine::Neutral.transition(env)
     }
   }
 }
 
+// ======================
+// === BattleStrategy ===
+// ======================
+
+// What a Pokémon does when it's free to pick its next move
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Action {
+  Fast,
+  Charged(ChargedChoice),
+  Wait,
+}
+
+// Picks the next move of `me`, given the state of both sides
+pub trait BattleStrategy {
+  fn decide(&self, me: &TurnState, opponent: &TurnState) -> Action;
+}
+
+// In order:
+// - a fast move that KOs goes first
+// - against no shields, the cheapest charged move that KOs goes as soon as
+//   there's energy for it
+// - once there's energy for the move with the best damage per energy, bait
+//   with the cheaper one if the opponent has shields, otherwise throw it
+// - fast move otherwise
+#[derive(Debug, Copy, Clone)]
+pub struct DefaultStrategy;
+
+impl BattleStrategy for DefaultStrategy {
+  fn decide(&self, me: &TurnState, opponent: &TurnState) -> Action {
+    if me.fast_damage(opponent) >= opponent.health() {
+      return Action::Fast;
+    }
+
+    let choices = [ChargedChoice::Main, ChargedChoice::Other];
+    let energy = |choice: &ChargedChoice| -me.charged_move(*choice).energy;
+
+    if !opponent.shields().available() {
+      let lethal = choices
+        .iter()
+        .filter(|c| me.can_throw(**c) && me.would_charged_kill(**c, opponent))
+        .min_by_key(|c| energy(c));
+      if let Some(choice) = lethal {
+        return Action::Charged(*choice);
+      }
+    }
+
+    let dpe = |choice: &ChargedChoice| me.charged_damage(*choice, opponent) as f64 / energy(choice) as f64;
+    let (best, other) = if dpe(&ChargedChoice::Main) > dpe(&ChargedChoice::Other) {
+      (ChargedChoice::Main, ChargedChoice::Other)
+    } else {
+      (ChargedChoice::Other, ChargedChoice::Main)
+    };
+
+    if !me.can_throw(best) {
+      Action::Fast
+    } else if energy(&best) > energy(&other) && opponent.shields().available() {
+      Action::Charged(other)
+    } else {
+      Action::Charged(best)
+    }
+  }
+}
+
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
 pub enum Shields {
   Two,
@@ -100,10 +137,138 @@ impl Shields {
   }
 }
 
+// ====================
+// === ShieldPolicy ===
+// ====================
+
+// A charged move about to hit a Pokémon that still has shields
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct IncomingCharged {
+  pub damage: i16,
+  pub health: i16,
+  pub max_health: i16,
+  pub shields: Shields,
+}
+
+impl IncomingCharged {
+  pub fn is_lethal(&self) -> bool {
+    self.damage >= self.health
+  }
+}
+
+// Decides whether to shield a charged move
+pub trait ShieldPolicy {
+  fn shield(&self, incoming: &IncomingCharged) -> bool;
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct AlwaysShield;
+
+impl ShieldPolicy for AlwaysShield {
+  fn shield(&self, _: &IncomingCharged) -> bool {
+    true
+  }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct NeverShield;
+
+impl ShieldPolicy for NeverShield {
+  fn shield(&self, _: &IncomingCharged) -> bool {
+    false
+  }
+}
+
+// Shields hits that would KO, or take more than `hp_fraction` of the max HP
+#[derive(Debug, Copy, Clone)]
+pub struct ShieldThreshold {
+  pub hp_fraction: f64,
+}
+
+impl ShieldPolicy for ShieldThreshold {
+  fn shield(&self, incoming: &IncomingCharged) -> bool {
+    incoming.is_lethal() || incoming.damage as f64 > self.hp_fraction * incoming.max_health as f64
+  }
+}
+
+// Follows another policy, but keeps the last shield for good
+#[derive(Debug, Copy, Clone)]
+pub struct SaveLastShield<P: ShieldPolicy>(pub P);
+
+impl<P: ShieldPolicy> ShieldPolicy for SaveLastShield<P> {
+  fn shield(&self, incoming: &IncomingCharged) -> bool {
+    incoming.shields == Shields::Two && self.0.shield(incoming)
+  }
+}
+
+// How to settle moves whose buffs only apply some of the time
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum BuffProcs {
+  // Every buff applies
+  Always,
+  // Only buffs with a 100% chance apply
+  Never,
+  // Buffs apply scaled by their chance, leaving fractional stat stages
+  ExpectedValue,
+  // Buffs apply at random, from the given seed
+  Random(u64),
+}
+
+// Rolls buff procs for a battle. Random procs come from a SplitMix64 sequence,
+// so the same seed always plays out the same battle.
+#[derive(Copy, Clone)]
+struct BuffRoller {
+  procs: BuffProcs,
+  seed: u64,
+}
+
+impl BuffRoller {
+  fn new(procs: BuffProcs) -> BuffRoller {
+    let seed = match procs {
+      BuffProcs::Random(seed) => seed,
+      _ => 0,
+    };
+    BuffRoller { procs, seed }
+  }
+
+  // In [0, 1)
+  fn next_random(&mut self) -> f64 {
+    self.seed = self.seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = self.seed;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+  }
+
+  // How much of the buffs' stage changes to apply
+  fn roll(&mut self, buffs: &MoveBuffs) -> f64 {
+    match self.procs {
+      BuffProcs::Always => 1.,
+      BuffProcs::Never => if buffs.chance >= 1. { 1. } else { 0. },
+      BuffProcs::ExpectedValue => buffs.chance,
+      BuffProcs::Random(_) => if self.next_random() < buffs.chance { 1. } else { 0. },
+    }
+  }
+}
+
+// A move landing: its damage, and the stat stage changes to its target
+#[derive(Debug, Copy, Clone, Default)]
+struct Hit {
+  damage: i16,
+  attack_stage: f64,
+  defense_stage: f64,
+}
+
 #[derive(Copy, Clone)]
 pub struct PokemonState {
   health: i16,
   energy: i16,
+  // Energy gained past the cap and lost
+  wasted_energy: i16,
+  // Fractional only with BuffProcs::ExpectedValue
+  attack_stage: f64,
+  defense_stage: f64,
   shields: Shields,
   state: MoveStateMachine,
 }
@@ -116,6 +281,9 @@ impl PokemonState {
     PokemonState {
       health: pokemon.stamina() as _,
       energy: 0,
+      wasted_energy: 0,
+      attack_stage: 0.,
+      defense_stage: 0.,
       shields,
       state: MoveStateMachine::Neutral
     }
@@ -142,65 +310,147 @@ impl std::fmt::Display for PokemonState {
 pub struct TurnState<'a> {
   state: PokemonState,
   instance: &'a PokemonInstance,
+  settings: &'a CombatSettings,
+  stat_stages: &'a StatStageSettings,
+  shield_policy: &'a dyn ShieldPolicy,
+  strategy: &'a dyn BattleStrategy,
 }
 
 impl<'a> TurnState<'a> {
   pub fn new(
     state: PokemonState,
     instance: &'a PokemonInstance,
+    settings: &'a CombatSettings,
+    stat_stages: &'a StatStageSettings,
+    shield_policy: &'a dyn ShieldPolicy,
+    strategy: &'a dyn BattleStrategy,
   ) -> TurnState<'a> {
     TurnState {
-      state, instance,
+      state, instance, settings, stat_stages, shield_policy, strategy,
     }
   }
-  
-  fn register_fast(&mut self, opponent: &TurnState<'a>) -> i16 { 
-    let damage = self.instance.fast_move.calculate(&self.instance, &opponent.instance);
-    let energy = self.instance.fast_move.energy;
 
-    self.state.energy += energy;
-    // self.defend_fast(damage)
-    damage
+  pub fn instance(&self) -> &PokemonInstance {
+    self.instance
   }
 
-  fn register_charged(&mut self, choice: ChargedChoice, opponent: &TurnState<'a>) -> i16 {
-    let charged_move = match choice {
+  pub fn health(&self) -> i16 {
+    self.state.health
+  }
+
+  pub fn energy(&self) -> i16 {
+    self.state.energy
+  }
+
+  pub fn shields(&self) -> Shields {
+    self.state.shields
+  }
+
+  pub fn charged_move(&self, choice: ChargedChoice) -> &ChargedMove {
+    match choice {
       ChargedChoice::Main => &self.instance.charged_move1,
       ChargedChoice::Other => &self.instance.charged_move2,
-    };
+    }
+  }
 
-    let damage = charged_move.calculate(&self.instance, &opponent.instance);
-    let energy_expenditure = charged_move.energy;
-    let current_energy = self.state.energy;
+  pub fn can_throw(&self, choice: ChargedChoice) -> bool {
+    self.state.energy + self.charged_move(choice).energy >= 0
+  }
 
-    self.state.energy = current_energy + energy_expenditure;
-    assert!(self.state.energy >= 0);
-    println!("{} {}", current_energy, energy_expenditure);
+  // Damage against `opponent` with both sides' current stat stages
+  pub fn fast_damage(&self, opponent: &TurnState) -> i16 {
+    self.instance.fast_move.calculate(&self.staged(), &opponent.staged(), self.settings)
+  }
 
-    // self.defend_charged(damage)
-    damage
+  pub fn charged_damage(&self, choice: ChargedChoice, opponent: &TurnState) -> i16 {
+    self.charged_move(choice).calculate(&self.staged(), &opponent.staged(), self.settings)
   }
 
-  fn defend_fast(&mut self, damage: i16) -> bool {
-    self.state.health = i16::max(0, self.state.health - damage) as _;
-    self.state.health == 0
+  // Shields aside
+  pub fn would_charged_kill(&self, choice: ChargedChoice, opponent: &TurnState) -> bool {
+    self.charged_damage(choice, opponent) >= opponent.state.health
+  }
+
+  // The instance with its current stat stages applied
+  fn staged(&self) -> PokemonInstance {
+    self.instance.clone().with_stat_stages(
+      self.stat_stages.attack_multiplier_at(self.state.attack_stage),
+      self.stat_stages.defense_multiplier_at(self.state.defense_stage),
+    )
   }
 
-  fn defend_charged(&mut self, damage: i16) -> bool {
-    match self.state.shields {
-      Shields::None => self.state.health = i16::max(0, self.state.health as i16 - damage) as _,
-      _ => self.state.shields = self.state.shields.transition(())
+  fn change_stages(&mut self, attack: f64, defense: f64) {
+    let (min, max) = (self.stat_stages.minimum_stage as f64, self.stat_stages.maximum_stage as f64);
+    self.state.attack_stage = (self.state.attack_stage + attack).clamp(min, max);
+    self.state.defense_stage = (self.state.defense_stage + defense).clamp(min, max);
+  }
+
+  // Buffs to the user apply right away, the ones to the target come with the hit
+  fn apply_buffs(&mut self, buffs: Option<MoveBuffs>, damage: i16, roller: &mut BuffRoller) -> Hit {
+    match buffs {
+      Some(buffs) => {
+        let proc = roller.roll(&buffs);
+        self.change_stages(buffs.attacker_attack as f64 * proc, buffs.attacker_defense as f64 * proc);
+        Hit {
+          damage,
+          attack_stage: buffs.target_attack as f64 * proc,
+          defense_stage: buffs.target_defense as f64 * proc,
+        }
+      },
+      None => Hit { damage, ..Default::default() },
+    }
+  }
+
+  fn register_fast(&mut self, opponent: &TurnState<'a>, roller: &mut BuffRoller) -> Hit {
+    let damage = self.fast_damage(opponent);
+    let energy = self.instance.fast_move.energy;
+    let gained = i16::min(energy, self.settings.max_energy - self.state.energy);
+
+    self.state.energy += gained;
+    self.state.wasted_energy += energy - gained;
+    self.apply_buffs(self.instance.fast_move.buffs, damage, roller)
+  }
+
+  // None, and nothing spent, if there isn't the energy for the move
+  fn register_charged(&mut self, choice: ChargedChoice, opponent: &TurnState<'a>, roller: &mut BuffRoller) -> Option<Hit> {
+    if !self.can_throw(choice) {
+      return None;
+    }
+    let damage = self.charged_damage(choice, opponent);
+    let (energy, buffs) = {
+      let charged_move = self.charged_move(choice);
+      (charged_move.energy, charged_move.buffs)
     };
+
+    self.state.energy += energy;
+    Some(self.apply_buffs(buffs, damage, roller))
+  }
+
+  fn defend_fast(&mut self, hit: Hit) -> bool {
+    self.change_stages(hit.attack_stage, hit.defense_stage);
+    self.state.health = i16::max(0, self.state.health - hit.damage) as _;
     self.state.health == 0
   }
 
-  fn would_charged_kill(&self, choice: ChargedChoice, opponent: &TurnState<'a>) -> bool {
-    let charged_move = match choice {
-      ChargedChoice::Main => &self.instance.charged_move1,
-      ChargedChoice::Other => &self.instance.charged_move2,
+  // Shields block the damage, not the stat stage changes
+  fn defend_charged(&mut self, hit: Option<Hit>) -> bool {
+    let hit = match hit {
+      Some(hit) => hit,
+      None => return self.state.health == 0,
+    };
+    self.change_stages(hit.attack_stage, hit.defense_stage);
+    let incoming = IncomingCharged {
+      damage: hit.damage,
+      health: self.state.health,
+      max_health: self.instance.stamina() as _,
+      shields: self.state.shields,
     };
-    let damage = charged_move.calculate(&self.instance, &opponent.instance);
-    damage > opponent.state.health
+    if self.state.shields.available() && self.shield_policy.shield(&incoming) {
+      self.state.shields = self.state.shields.transition(());
+    } else {
+      self.state.health = i16::max(0, self.state.health - hit.damage);
+    }
+    self.state.health == 0
   }
 
   fn wins_cmp_tie(&self, opponent: &TurnState<'a>) -> bool {
@@ -217,15 +467,46 @@ pub enum BattleState {
   Win,
   Loss,
   Draw,
+  // Ran out of time, settled by a tiebreak
+  Timeout(Tiebreak),
   Continue(PokemonState, MoveStateMachine, PokemonState, MoveStateMachine)
 }
 
+// Who has more Pokémon left at timeout, then more HP left, from the first
+// side's point of view
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Tiebreak {
+  Win,
+  Loss,
+  Draw,
+}
+
+impl Tiebreak {
+  // (Pokémon left, HP left) of each side
+  fn new(side1: (usize, i32), side2: (usize, i32)) -> Tiebreak {
+    match side1.cmp(&side2) {
+      std::cmp::Ordering::Greater => Tiebreak::Win,
+      std::cmp::Ordering::Less => Tiebreak::Loss,
+      std::cmp::Ordering::Equal => Tiebreak::Draw,
+    }
+  }
+}
+
+// How a finished battle went, from the first Pokémon's point of view
+#[derive(Copy, Clone)]
+pub struct BattleResult {
+  pub state: BattleState,
+  pub turns: u16,
+  pub wasted_energy: (i16, i16),
+}
+
 impl std::fmt::Display for BattleState {
   fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
     match self {
       BattleState::Win => write!(f, "Win"),
       BattleState::Loss => write!(f, "Loss"),
       BattleState::Draw => write!(f, "Draw"),
+      BattleState::Timeout(tiebreak) => write!(f, "Timeout ({:?})", tiebreak),
       BattleState::Continue(a, _, b, _) => write!(f, "({}, {})", a, b)
     }
   }
@@ -237,6 +518,8 @@ pub struct PokemonStateDTO {
   health: i16,
   energy: i16,
   shields: i32,
+  attack_stage: f64,
+  defense_stage: f64,
 }
 
 pub struct TurnStateDTO {
@@ -253,6 +536,7 @@ impl From<(&PokemonInstance, &PokemonState, &MoveStateMachine)> for PokemonState
         MoveStateMachine::RegisterCharged(ChargedChoice::Main) => format!("uses charged move {}", inst.charged_move1.uid),
         MoveStateMachine::RegisterCharged(ChargedChoice::Other) => format!("uses charged move {}", inst.charged_move2.uid),
         MoveStateMachine::Idle(_) => format!("waits"),
+        MoveStateMachine::Wait => "does nothing".to_owned(),
         MoveStateMachine::Neutral => format!("WTF")
       },
       health: state.health,
@@ -261,14 +545,20 @@ impl From<(&PokemonInstance, &PokemonState, &MoveStateMachine)> for PokemonState
         Shields::None => 0,
         Shields::One => 1,
         Shields::Two => 2
-      }
+      },
+      attack_stage: state.attack_stage,
+      defense_stage: state.defense_stage,
     }
   }
 }
 
 impl std::fmt::Display for PokemonStateDTO {
   fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-    write!(f, "{} [H {:>3} E {:>3} S {:1}] {}", self.pokemon_id, self.health, self.energy, self.shields, self.action)
+    write!(
+      f,
+      "{} [H {:>3} E {:>3} S {:1} A {:+} D {:+}] {}",
+      self.pokemon_id, self.health, self.energy, self.shields, self.attack_stage, self.defense_stage, self.action
+    )
   }
 }
 
@@ -278,14 +568,72 @@ impl std::fmt::Display for TurnStateDTO {
   }
 }
 
+// Lands the moves both sides registered this turn
+fn resolve_turn<'a>(
+  pokemon1: &mut TurnState<'a>,
+  new_state1: MoveStateMachine,
+  pokemon2: &mut TurnState<'a>,
+  new_state2: MoveStateMachine,
+  roller: &mut BuffRoller,
+) {
+  match (new_state1, new_state2) {
+    (MoveStateMachine::RegisterCharged(choice1), MoveStateMachine::RegisterCharged(choice2)) => {
+      // CMP Tie
+      if pokemon1.wins_cmp_tie(pokemon2) {
+        pokemon2.defend_charged(pokemon1.register_charged(choice1, pokemon2, roller));
+        pokemon1.defend_charged(pokemon2.register_charged(choice2, pokemon1, roller)); // TODO block if it has been killed
+      } else {
+        pokemon1.defend_charged(pokemon2.register_charged(choice2, pokemon1, roller));
+        pokemon2.defend_charged(pokemon1.register_charged(choice1, pokemon2, roller)); // TODO block if it has been killed
+      }
+    },
+    (MoveStateMachine::RegisterCharged(choice), MoveStateMachine::Idle(_) | MoveStateMachine::Wait) => {
+      pokemon2.defend_charged(pokemon1.register_charged(choice, pokemon2, roller));
+    },
+    (MoveStateMachine::RegisterCharged(choice), MoveStateMachine::RegisterFast) => {
+      pokemon2.defend_charged(pokemon1.register_charged(choice, pokemon2, roller));
+      pokemon1.defend_fast(pokemon2.register_fast(pokemon1, roller));
+    },
+    (MoveStateMachine::Idle(_) | MoveStateMachine::Wait, MoveStateMachine::RegisterCharged(choice)) => {
+      pokemon1.defend_charged(pokemon2.register_charged(choice, pokemon1, roller));
+    },
+    (MoveStateMachine::RegisterFast, MoveStateMachine::RegisterCharged(choice)) => {
+      pokemon1.defend_charged(pokemon2.register_charged(choice, pokemon1, roller));
+      pokemon2.defend_fast(pokemon1.register_fast(pokemon2, roller));
+    },
+    (MoveStateMachine::RegisterFast, MoveStateMachine::Idle(_) | MoveStateMachine::Wait) => {
+      pokemon2.defend_fast(pokemon1.register_fast(pokemon2, roller));
+    },
+    (MoveStateMachine::Idle(_) | MoveStateMachine::Wait, MoveStateMachine::RegisterFast) => {
+      pokemon1.defend_fast(pokemon2.register_fast(pokemon1, roller));
+    },
+    (MoveStateMachine::RegisterFast, MoveStateMachine::RegisterFast) => {
+      pokemon2.defend_fast(pokemon1.register_fast(pokemon2, roller));
+      pokemon1.defend_fast(pokemon2.register_fast(pokemon1, roller));
+    },
+    (MoveStateMachine::Idle(_) | MoveStateMachine::Wait, MoveStateMachine::Idle(_) | MoveStateMachine::Wait) => {},
+    (MoveStateMachine::Neutral, _) => unreachable!(),
+    (_, MoveStateMachine::Neutral) => unreachable!(),
+  }
+}
+
 pub struct Battle {
   pokemon_instances: (PokemonInstance, PokemonInstance),
+  settings: CombatSettings,
+  stat_stages: StatStageSettings,
+  buff_roller: BuffRoller,
+  shield_policies: (Box<dyn ShieldPolicy>, Box<dyn ShieldPolicy>),
+  strategies: (Box<dyn BattleStrategy>, Box<dyn BattleStrategy>),
   turn: u16,
-  state: BattleState
+  time_limit: u16,
+  state: BattleState,
+  wasted_energy: (i16, i16),
 }
 
 impl Battle {
   pub fn new(
+    settings: &CombatSettings,
+    stat_stages: &StatStageSettings,
     pokemon1: PokemonInstance,
     pokemon2: PokemonInstance,
     shields1: Shields,
@@ -302,7 +650,81 @@ impl Battle {
         pokemon1,
         pokemon2,
       ),
-      turn: 0
+      settings: *settings,
+      stat_stages: stat_stages.clone(),
+      buff_roller: BuffRoller::new(BuffProcs::Never),
+      shield_policies: (Box::new(AlwaysShield), Box::new(AlwaysShield)),
+      strategies: (Box::new(DefaultStrategy), Box::new(DefaultStrategy)),
+      turn: 0,
+      time_limit: settings.round_turns(),
+      wasted_energy: (0, 0),
+    }
+  }
+
+  // Buffs that aren't certain to apply don't, unless told otherwise
+  pub fn with_buff_procs(self, procs: BuffProcs) -> Battle {
+    Battle { buff_roller: BuffRoller::new(procs), ..self }
+  }
+
+  // Both sides shield every charged move they can, unless told otherwise
+  pub fn with_shield_policies<P1, P2>(self, policy1: P1, policy2: P2) -> Battle
+  where
+    P1: ShieldPolicy + 'static,
+    P2: ShieldPolicy + 'static,
+  {
+    Battle { shield_policies: (Box::new(policy1), Box::new(policy2)), ..self }
+  }
+
+  // Both sides play DefaultStrategy, unless told otherwise
+  pub fn with_strategies<S1, S2>(self, strategy1: S1, strategy2: S2) -> Battle
+  where
+    S1: BattleStrategy + 'static,
+    S2: BattleStrategy + 'static,
+  {
+    Battle { strategies: (Box::new(strategy1), Box::new(strategy2)), ..self }
+  }
+
+  // Battles time out after the game master's round duration, unless told
+  // otherwise
+  pub fn with_time_limit(mut self, turns: u16) -> Battle {
+    self.time_limit = turns;
+    self.check_timer();
+    self
+  }
+
+  // Same as `new`, but refuses Pokémon that break the league's rules
+  pub fn in_league(
+    league: &League,
+    settings: &CombatSettings,
+    stat_stages: &StatStageSettings,
+    pokemon1: PokemonInstance,
+    pokemon2: PokemonInstance,
+    shields1: Shields,
+    shields2: Shields,
+  ) -> Result<Battle, Error> {
+    league.check(&pokemon1)?;
+    league.check(&pokemon2)?;
+    Ok(Battle::new(settings, stat_stages, pokemon1, pokemon2, shields1, shields2))
+  }
+
+  // None until the battle is over
+  pub fn result(&self) -> Option<BattleResult> {
+    match self.state {
+      BattleState::Continue(..) => None,
+      state => Some(BattleResult {
+        state,
+        turns: self.turn,
+        wasted_energy: self.wasted_energy,
+      }),
+    }
+  }
+
+  fn check_timer(&mut self) {
+    if let BattleState::Continue(state1, _, state2, _) = self.state {
+      if self.turn >= self.time_limit {
+        let tiebreak = Tiebreak::new((1, state1.health as i32), (1, state2.health as i32));
+        self.state = BattleState::Timeout(tiebreak);
+      }
     }
   }
 }
@@ -312,68 +734,25 @@ impl<'a> Iterator for Battle {
 
   fn next(&mut self) -> Option<TurnStateDTO> {
     if let BattleState::Continue(
-      state_attacker, move_state_attacker,
-      state_defender, move_state_defender
+      state_attacker, _,
+      state_defender, _
     ) = self.state {
       let mut pokemon1 = TurnState::new(
-        state_attacker, &self.pokemon_instances.0,
+        state_attacker, &self.pokemon_instances.0, &self.settings, &self.stat_stages,
+        self.shield_policies.0.as_ref(), self.strategies.0.as_ref(),
       );
       let mut pokemon2 = TurnState::new(
-        state_defender, &self.pokemon_instances.1
+        state_defender, &self.pokemon_instances.1, &self.settings, &self.stat_stages,
+        self.shield_policies.1.as_ref(), self.strategies.1.as_ref(),
       );
 
       let new_state1 = pokemon1.transition(&pokemon2);
       let new_state2 = pokemon2.transition(&pokemon1);
 
-      {
-        let p1 = &state_attacker;
-        let p2 = &state_defender;
-        println!("\n  === Turn {:>5} ===", self.turn);
-        println!("{:?} -> {:?} H {:>4} E {:>4} {:?}", move_state_attacker, new_state1, p1.health, p1.energy, p1.shields);
-        println!("{:?} -> {:?} H {:>4} E {:>4} {:?}", move_state_defender, new_state2, p2.health, p2.energy, p2.shields);
-      };
-
-      match (new_state1, new_state2) {
-        (MoveStateMachine::RegisterCharged(choice1), MoveStateMachine::RegisterCharged(choice2)) => {
-          // CMP Tie
-          if pokemon1.wins_cmp_tie(&pokemon2) {
-            pokemon2.defend_charged(pokemon1.register_charged(choice1, &pokemon2));
-            pokemon1.defend_charged(pokemon2.register_charged(choice2, &pokemon1)); // TODO block if it has been killed
-          } else {
-            pokemon1.defend_charged(pokemon2.register_charged(choice2, &pokemon1));
-            pokemon2.defend_charged(pokemon1.register_charged(choice1, &pokemon2)); // TODO block if it has been killed
-          }
-        },
-        (MoveStateMachine::RegisterCharged(choice), MoveStateMachine::Idle(_)) => {
-          pokemon2.defend_charged(pokemon1.register_charged(choice, &pokemon2));
-        },
-        (MoveStateMachine::RegisterCharged(choice), MoveStateMachine::RegisterFast) => {
-          pokemon2.defend_charged(pokemon1.register_charged(choice, &pokemon2));
-          pokemon1.defend_fast(pokemon2.register_fast(&pokemon1));
-        },
-        (MoveStateMachine::Idle(_), MoveStateMachine::RegisterCharged(choice)) => {
-          pokemon1.defend_charged(pokemon2.register_charged(choice, &pokemon1));
-        },
-        (MoveStateMachine::RegisterFast, MoveStateMachine::RegisterCharged(choice)) => {
-          pokemon1.defend_charged(pokemon2.register_charged(choice, &pokemon1));
-          pokemon2.defend_fast(pokemon1.register_fast(&pokemon2));
-        },
-        (MoveStateMachine::RegisterFast, MoveStateMachine::Idle(_)) => {
-          pokemon2.defend_fast(pokemon1.register_fast(&pokemon2));
-        },
-        (MoveStateMachine::Idle(_), MoveStateMachine::RegisterFast) => {
-          pokemon2.register_fast(&pokemon1);
-        },
-        (MoveStateMachine::RegisterFast, MoveStateMachine::RegisterFast) => {
-          pokemon2.defend_fast(pokemon1.register_fast(&pokemon2));
-          pokemon1.defend_fast(pokemon2.register_fast(&pokemon1));
-        },
-        (MoveStateMachine::Idle(_), MoveStateMachine::Idle(_)) => {},
-        (MoveStateMachine::Neutral, _) => unreachable!(),
-        (_, MoveStateMachine::Neutral) => unreachable!(),
-      }
+      resolve_turn(&mut pokemon1, new_state1, &mut pokemon2, new_state2, &mut self.buff_roller);
 
       self.turn += 1;
+      self.wasted_energy = (pokemon1.state.wasted_energy, pokemon2.state.wasted_energy);
       pokemon1.state.state = new_state1;
       pokemon2.state.state = new_state2;
 
@@ -383,6 +762,7 @@ impl<'a> Iterator for Battle {
         (a, 0) if a > 0 => BattleState::Win,
         (_, _) => BattleState::Continue(pokemon1.state, new_state1, pokemon2.state, new_state2)
       };
+      self.check_timer();
 
       // Some(self.state)
       Some(TurnStateDTO {
@@ -395,26 +775,414 @@ impl<'a> Iterator for Battle {
   }
 }
 
+// ==================
+// === TeamBattle ===
+// ==================
+
+// Picks which Pokémon a team sends in. `bench` holds the team's other
+// Pokémon still standing, in team order, and answers index into it.
+pub trait SwitchPolicy {
+  // Asked whenever the switch timer is ready and the active Pokémon is free to
+  // act; the incoming Pokémon spends the turn coming in
+  fn switch(&self, active: &TurnState, bench: &[TurnState], opponent: &TurnState) -> Option<usize>;
+  // Asked when the active Pokémon faints
+  fn replace(&self, bench: &[TurnState], opponent: &TurnState) -> usize;
+}
+
+// Never switches, and replaces fainted Pokémon in team order
+#[derive(Debug, Copy, Clone)]
+pub struct InOrder;
+
+impl SwitchPolicy for InOrder {
+  fn switch(&self, _: &TurnState, _: &[TurnState], _: &TurnState) -> Option<usize> {
+    None
+  }
+
+  fn replace(&self, _: &[TurnState], _: &TurnState) -> usize {
+    0
+  }
+}
+
+// A Pokémon fainting: its position in the team and the turn it went down on
+#[derive(Debug, Clone, PartialEq)]
+pub struct Faint {
+  pub pokemon_id: String,
+  pub slot: usize,
+  pub turn: u16,
+}
+
+// How a finished team battle went, from the first team's point of view
+#[derive(Clone)]
+pub struct TeamBattleResult {
+  pub state: BattleState,
+  pub turns: u16,
+  pub faints: (Vec<Faint>, Vec<Faint>),
+  // Energy each team lost to the cap, over all of its Pokémon
+  pub wasted_energy: (i16, i16),
+}
+
+// One side of a team battle. Benched Pokémon keep their health and energy,
+// shields belong to the team.
+struct Team {
+  instances: Vec<PokemonInstance>,
+  states: Vec<PokemonState>,
+  active: usize,
+  shields: Shields,
+  // Turns until the team can switch again
+  switch_timer: u16,
+  faints: Vec<Faint>,
+  shield_policy: Box<dyn ShieldPolicy>,
+  strategy: Box<dyn BattleStrategy>,
+  switch_policy: Box<dyn SwitchPolicy>,
+}
+
+impl Team {
+  fn new(instances: Vec<PokemonInstance>) -> Team {
+    Team {
+      states: instances.iter().map(|p| PokemonState::new(p, Shields::Two)).collect(),
+      instances,
+      active: 0,
+      shields: Shields::Two,
+      switch_timer: 0,
+      faints: Vec::new(),
+      shield_policy: Box::new(AlwaysShield),
+      strategy: Box::new(DefaultStrategy),
+      switch_policy: Box::new(InOrder),
+    }
+  }
+
+  // Slots of the Pokémon that could come in
+  fn bench(&self) -> Vec<usize> {
+    (0..self.instances.len())
+      .filter(|slot| *slot != self.active && self.states[*slot].health > 0)
+      .collect()
+  }
+
+  fn is_out(&self) -> bool {
+    self.states.iter().all(|s| s.health == 0)
+  }
+
+  // Pokémon and total HP left
+  fn left(&self) -> (usize, i32) {
+    let standing = self.states.iter().filter(|s| s.health > 0);
+    (standing.clone().count(), standing.map(|s| s.health as i32).sum())
+  }
+
+  fn wasted_energy(&self) -> i16 {
+    self.states.iter().map(|s| s.wasted_energy).sum()
+  }
+
+  fn turn_state<'a>(&'a self, slot: usize, settings: &'a CombatSettings, stat_stages: &'a StatStageSettings) -> TurnState<'a> {
+    TurnState::new(
+      self.states[slot], &self.instances[slot], settings, stat_stages,
+      self.shield_policy.as_ref(), self.strategy.as_ref(),
+    )
+  }
+
+  // Stat stages don't survive switching out, and the incoming Pokémon starts
+  // from scratch
+  fn switch_to(&mut self, slot: usize) {
+    let outgoing = &mut self.states[self.active];
+    outgoing.attack_stage = 0.;
+    outgoing.defense_stage = 0.;
+    outgoing.state = MoveStateMachine::Neutral;
+    self.active = slot;
+    self.states[slot].shields = self.shields;
+    self.states[slot].state = MoveStateMachine::Neutral;
+  }
+}
+
+pub struct TeamBattle {
+  teams: (Team, Team),
+  settings: CombatSettings,
+  stat_stages: StatStageSettings,
+  buff_roller: BuffRoller,
+  turn: u16,
+  time_limit: u16,
+  state: BattleState,
+}
+
+impl TeamBattle {
+  // Teams of 1 to 3 Pokémon, the first one leading
+  pub fn new(
+    settings: &CombatSettings,
+    stat_stages: &StatStageSettings,
+    team1: Vec<PokemonInstance>,
+    team2: Vec<PokemonInstance>,
+  ) -> Result<TeamBattle, Error> {
+    for team in &[&team1, &team2] {
+      if team.is_empty() || team.len() > 3 {
+        return Err(Error::BoundsError(format!("Teams have 1 to 3 Pokémon, got {}", team.len())));
+      }
+    }
+    let teams = (Team::new(team1), Team::new(team2));
+    Ok(TeamBattle {
+      state: BattleState::Continue(
+        teams.0.states[0], MoveStateMachine::Neutral,
+        teams.1.states[0], MoveStateMachine::Neutral,
+      ),
+      teams,
+      settings: *settings,
+      stat_stages: stat_stages.clone(),
+      buff_roller: BuffRoller::new(BuffProcs::Never),
+      turn: 0,
+      time_limit: settings.round_turns(),
+    })
+  }
+
+  // Same as `new`, but refuses Pokémon that break the league's rules
+  pub fn in_league(
+    league: &League,
+    settings: &CombatSettings,
+    stat_stages: &StatStageSettings,
+    team1: Vec<PokemonInstance>,
+    team2: Vec<PokemonInstance>,
+  ) -> Result<TeamBattle, Error> {
+    league.check_team(&team1)?;
+    league.check_team(&team2)?;
+    TeamBattle::new(settings, stat_stages, team1, team2)
+  }
+
+  // Two shields per team, unless told otherwise
+  pub fn with_shields(mut self, shields1: Shields, shields2: Shields) -> TeamBattle {
+    for (team, shields) in [(&mut self.teams.0, shields1), (&mut self.teams.1, shields2)] {
+      team.shields = shields;
+      team.states.iter_mut().for_each(|s| s.shields = shields);
+    }
+    self.state = BattleState::Continue(
+      self.teams.0.states[0], MoveStateMachine::Neutral,
+      self.teams.1.states[0], MoveStateMachine::Neutral,
+    );
+    self
+  }
+
+  pub fn with_time_limit(mut self, turns: u16) -> TeamBattle {
+    self.time_limit = turns;
+    self.check_timer();
+    self
+  }
+
+  pub fn with_buff_procs(self, procs: BuffProcs) -> TeamBattle {
+    TeamBattle { buff_roller: BuffRoller::new(procs), ..self }
+  }
+
+  pub fn with_shield_policies<P1, P2>(mut self, policy1: P1, policy2: P2) -> TeamBattle
+  where
+    P1: ShieldPolicy + 'static,
+    P2: ShieldPolicy + 'static,
+  {
+    self.teams.0.shield_policy = Box::new(policy1);
+    self.teams.1.shield_policy = Box::new(policy2);
+    self
+  }
+
+  pub fn with_strategies<S1, S2>(mut self, strategy1: S1, strategy2: S2) -> TeamBattle
+  where
+    S1: BattleStrategy + 'static,
+    S2: BattleStrategy + 'static,
+  {
+    self.teams.0.strategy = Box::new(strategy1);
+    self.teams.1.strategy = Box::new(strategy2);
+    self
+  }
+
+  // Both teams play InOrder, unless told otherwise
+  pub fn with_switch_policies<P1, P2>(mut self, policy1: P1, policy2: P2) -> TeamBattle
+  where
+    P1: SwitchPolicy + 'static,
+    P2: SwitchPolicy + 'static,
+  {
+    self.teams.0.switch_policy = Box::new(policy1);
+    self.teams.1.switch_policy = Box::new(policy2);
+    self
+  }
+
+  // None until the battle is over
+  pub fn result(&self) -> Option<TeamBattleResult> {
+    match self.state {
+      BattleState::Continue(..) => None,
+      state => Some(TeamBattleResult {
+        state,
+        turns: self.turn,
+        faints: (self.teams.0.faints.clone(), self.teams.1.faints.clone()),
+        wasted_energy: (self.teams.0.wasted_energy(), self.teams.1.wasted_energy()),
+      }),
+    }
+  }
+
+  fn check_timer(&mut self) {
+    if let BattleState::Continue(..) = self.state {
+      if self.turn >= self.time_limit {
+        self.state = BattleState::Timeout(Tiebreak::new(self.teams.0.left(), self.teams.1.left()));
+      }
+    }
+  }
+
+  // Where to switch `team`, if its switch policy wants to and it's allowed to
+  fn switch_choice(&self, team: &Team, opponent: &Team) -> Option<usize> {
+    let slots = team.bench();
+    let busy = matches!(team.states[team.active].state, MoveStateMachine::Idle(_));
+    if team.switch_timer > 0 || busy || slots.is_empty() {
+      return None;
+    }
+
+    let bench: Vec<TurnState> = slots.iter().map(|s| team.turn_state(*s, &self.settings, &self.stat_stages)).collect();
+    team.switch_policy
+      .switch(
+        &team.turn_state(team.active, &self.settings, &self.stat_stages),
+        &bench,
+        &opponent.turn_state(opponent.active, &self.settings, &self.stat_stages),
+      )
+      .and_then(|i| slots.get(i).copied())
+  }
+
+  // Who replaces the fainted Pokémon of `team`, if it has anyone left
+  fn replacement(&self, team: &Team, opponent: &Team) -> Option<usize> {
+    let slots = team.bench();
+    let bench: Vec<TurnState> = slots.iter().map(|s| team.turn_state(*s, &self.settings, &self.stat_stages)).collect();
+    let opponent = opponent.turn_state(opponent.active, &self.settings, &self.stat_stages);
+    // Out of range answers pick the first one
+    slots.first().map(|first| *slots.get(team.switch_policy.replace(&bench, &opponent)).unwrap_or(first))
+  }
+}
+
+impl Iterator for TeamBattle {
+  type Item = TurnStateDTO;
+
+  fn next(&mut self) -> Option<TurnStateDTO> {
+    if let BattleState::Continue(..) = self.state {} else {
+      return None;
+    }
+
+    let switches = (
+      self.switch_choice(&self.teams.0, &self.teams.1),
+      self.switch_choice(&self.teams.1, &self.teams.0),
+    );
+    let cooldown = self.settings.switch_cooldown_turns();
+    for (team, switch) in [(&mut self.teams.0, switches.0), (&mut self.teams.1, switches.1)] {
+      match switch {
+        Some(slot) => {
+          team.switch_to(slot);
+          team.switch_timer = cooldown;
+        },
+        None => team.switch_timer = team.switch_timer.saturating_sub(1),
+      }
+    }
+
+    let (before1, before2) = (self.teams.0.states[self.teams.0.active], self.teams.1.states[self.teams.1.active]);
+    let (after1, after2) = {
+      let mut pokemon1 = self.teams.0.turn_state(self.teams.0.active, &self.settings, &self.stat_stages);
+      let mut pokemon2 = self.teams.1.turn_state(self.teams.1.active, &self.settings, &self.stat_stages);
+      // Coming in takes the turn
+      let new_state1 = if switches.0.is_some() { MoveStateMachine::Wait } else { pokemon1.transition(&pokemon2) };
+      let new_state2 = if switches.1.is_some() { MoveStateMachine::Wait } else { pokemon2.transition(&pokemon1) };
+
+      resolve_turn(&mut pokemon1, new_state1, &mut pokemon2, new_state2, &mut self.buff_roller);
+      pokemon1.state.state = new_state1;
+      pokemon2.state.state = new_state2;
+      (pokemon1.state, pokemon2.state)
+    };
+
+    let dto = |team: &Team, before: &PokemonState, after: &PokemonState, switch: Option<usize>| {
+      let instance = &team.instances[team.active];
+      let mut dto: PokemonStateDTO = (instance, before, &after.state).into();
+      if switch.is_some() {
+        dto.action = format!("switches to {}", instance.pokemon.id);
+      }
+      dto
+    };
+    let turn = TurnStateDTO {
+      attacker: dto(&self.teams.0, &before1, &after1, switches.0),
+      defender: dto(&self.teams.1, &before2, &after2, switches.1),
+    };
+
+    for (team, after) in [(&mut self.teams.0, after1), (&mut self.teams.1, after2)] {
+      team.states[team.active] = after;
+      team.shields = after.shields;
+      if after.health == 0 {
+        team.faints.push(Faint {
+          pokemon_id: team.instances[team.active].pokemon.id.clone(),
+          slot: team.active,
+          turn: self.turn,
+        });
+      }
+    }
+    self.turn += 1;
+
+    // Faint swaps don't wait for, or reset, the switch timer
+    if after1.health == 0 {
+      if let Some(slot) = self.replacement(&self.teams.0, &self.teams.1) {
+        self.teams.0.switch_to(slot);
+      }
+    }
+    if after2.health == 0 {
+      if let Some(slot) = self.replacement(&self.teams.1, &self.teams.0) {
+        self.teams.1.switch_to(slot);
+      }
+    }
+
+    self.state = match (self.teams.0.is_out(), self.teams.1.is_out()) {
+      (true, true) => BattleState::Draw,
+      (true, false) => BattleState::Loss,
+      (false, true) => BattleState::Win,
+      (false, false) => {
+        let (team1, team2) = (&self.teams.0, &self.teams.1);
+        let (state1, state2) = (team1.states[team1.active], team2.states[team2.active]);
+        BattleState::Continue(state1, state1.state, state2, state2.state)
+      },
+    };
+    self.check_timer();
+
+    Some(turn)
+  }
+}
+
 #[cfg(test)]
 mod tests {
   use crate::model::pokemon::Level;
-  use crate::gamemaster::*;
+  use crate::gamemaster::test_fixture;
   use crate::model::mechanics::*;
   use super::*;
 
-  use std::convert::TryFrom;
+  // What each side does on every turn
+  fn timeline(battle: Battle) -> Vec<(String, String)> {
+    battle.map(|t| (t.attacker.action, t.defender.action)).collect()
+  }
+
+  // Turns on which one side registers a move, and the first it charges on
+  fn registered(actions: &[String]) -> (Vec<usize>, Option<usize>) {
+    let fast = actions.iter().enumerate().filter(|(_, a)| a.starts_with("uses fast move")).map(|(i, _)| i);
+    let charged = actions.iter().position(|a| a.starts_with("uses charged move"));
+    (fast.take_while(|i| charged.is_none_or(|c| *i < c)).collect(), charged)
+  }
+
+  // Turns on which a side took `action`
+  fn turns_of(side: &[&PokemonStateDTO], action: &str) -> Vec<usize> {
+    side.iter().enumerate().filter(|(_, p)| p.action.starts_with(action)).map(|(i, _)| i).collect()
+  }
+
+  // A side's health at the start of the given turns
+  fn health_at(side: &[&PokemonStateDTO], turns: &[usize]) -> Vec<i16> {
+    turns.iter().map(|t| side[*t].health).collect()
+  }
+
+  fn sides(turns: &[TurnStateDTO]) -> (Vec<&PokemonStateDTO>, Vec<&PokemonStateDTO>) {
+    turns.iter().map(|t| (&t.attacker, &t.defender)).unzip()
+  }
 
   #[test]
   fn test_victreebel_vs_whiscash() {
     // https://pvpoke.com/battle/1500/victreebel-23-1-15-15-4-4-1/whiscash-28-0-14-13-4-4-1/22/1-2-1/0-2-1/
-    // let gms = std::fs::read_to_string("data/gamemaster.json").unwrap();
-    // let gm = serde_json::from_str::<GameMaster>(&gms).unwrap();
-    // let mech = Mechanics::try_from(gm).unwrap();
-    let mech = Mechanics::instance();
+    // TODO check against PvPoke's timeline; worked out by hand from the fixture:
+    // - Victreebel has 131 HP and Whiscash 180
+    // - Razor Leaf does 32 and Mud Shot 3, both registering on odd turns
+    // - Victreebel has the 35 energy for Leaf Blade after 5 Razor Leafs, but
+    //   the 6th KOs, and Whiscash never gets to the 75 energy for Blizzard
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
 
     let victreebel = mech.pokemon_instance(
       "VICTREEBEL",
-      Level { level: 23, a_half: false },
+      Level::new(23, false).unwrap(),
       1, 15, 15,
       "RAZOR_LEAF_FAST",
       "LEAF_BLADE",
@@ -423,32 +1191,44 @@ mod tests {
 
     let whiscash = mech.pokemon_instance(
       "WHISCASH",
-      Level { level: 28, a_half: false },
+      Level::new(28, false).unwrap(),
       0, 14, 13,
       "MUD_SHOT_FAST",
       "BLIZZARD",
       Some("MUD_BOMB"),
     ).unwrap();
 
-    let battle = Battle::new(victreebel, whiscash, Shields::Two, Shields::Two);
+    let mut battle = Battle::new(&mech.combat_settings, &mech.stat_stages, victreebel, whiscash, Shields::Two, Shields::Two);
+    let turns: Vec<TurnStateDTO> = battle.by_ref().collect();
+    assert_eq!(turns.len(), 12);
+    assert!(matches!(battle.result().unwrap().state, BattleState::Win));
 
-    let v: Vec<_> = battle.collect();
-    for (i, turn) in v.iter().enumerate() {
-      println!("{:>4}\n{}", i, turn);
-    }
+    let (victreebel, whiscash) = sides(&turns);
+    assert_eq!(turns_of(&victreebel, "uses fast move RAZOR_LEAF_FAST"), vec![1, 3, 5, 7, 9, 11]);
+    assert_eq!(turns_of(&whiscash, "uses fast move MUD_SHOT_FAST"), vec![1, 3, 5, 7, 9, 11]);
+    assert!(turns_of(&victreebel, "uses charged move").is_empty());
+    assert!(turns_of(&whiscash, "uses charged move").is_empty());
+
+    // Hits show from the next turn, so the 6th only shows as the Win
+    let even = [0, 2, 4, 6, 8, 10];
+    assert_eq!(health_at(&whiscash, &even), vec![180, 148, 116, 84, 52, 20]);
+    assert_eq!(health_at(&victreebel, &even), vec![131, 128, 125, 122, 119, 116]);
+    assert_eq!((victreebel[10].energy, whiscash[10].energy), (35, 45));
   }
 
   #[test]
   fn test_lucario_mirror() {
     // https://pvpoke.com/battle/1500/lucario-21-15-0-0-4-4-1/lucario-20.5-0-15-15-4-4-1/22/1-1-5/1-1-5/
-    // let gms = std::fs::read_to_string("data/gamemaster.json").unwrap();
-    // let gm = serde_json::from_str::<GameMaster>(&gms).unwrap();
-    // let mech = Mechanics::try_from(gm).unwrap();
-    let mech = Mechanics::instance();
+    // TODO check against PvPoke's timeline; worked out by hand from the fixture:
+    // - the attacker has 105 HP and the defender 113
+    // - Counter is super effective, doing 17 to the attacker and 16 to the
+    //   defender, on odd turns
+    // - the attacker goes down to the 7th, short of the 55 energy for a charged move
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
 
     let lucario_attacker = mech.pokemon_instance(
       "LUCARIO",
-      Level { level: 21, a_half: false },
+      Level::new(21, false).unwrap(),
       15, 0, 0,
       "COUNTER_FAST",
       "AURA_SPHERE",
@@ -457,7 +1237,7 @@ mod tests {
 
     let lucario_defender = mech.pokemon_instance(
       "LUCARIO",
-      Level { level: 20, a_half: true },
+      Level::new(20, true).unwrap(),
       0, 15, 15,
       "COUNTER_FAST",
       "AURA_SPHERE",
@@ -467,25 +1247,35 @@ mod tests {
     assert_eq!(lucario_attacker.cp(), 1480);
     assert_eq!(lucario_defender.cp(), 1488);
 
-    let battle = Battle::new(lucario_attacker, lucario_defender, Shields::Two, Shields::Two);
+    let mut battle = Battle::new(&mech.combat_settings, &mech.stat_stages, lucario_attacker, lucario_defender, Shields::Two, Shields::Two);
+    let turns: Vec<TurnStateDTO> = battle.by_ref().collect();
+    assert_eq!(turns.len(), 14);
+    assert!(matches!(battle.result().unwrap().state, BattleState::Loss));
 
-    let v: Vec<_> = battle.collect();
-    for (i, turn) in v.iter().enumerate() {
-      println!("{:>4}\n{}", i, turn);
-    }
+    let (attacker, defender) = sides(&turns);
+    let odd = vec![1, 3, 5, 7, 9, 11, 13];
+    assert_eq!(turns_of(&attacker, "uses fast move COUNTER_FAST"), odd);
+    assert_eq!(turns_of(&defender, "uses fast move COUNTER_FAST"), odd);
+    assert_eq!(health_at(&attacker, &odd), vec![105, 88, 71, 54, 37, 20, 3]);
+    assert_eq!(health_at(&defender, &odd), vec![113, 97, 81, 65, 49, 33, 17]);
+    assert_eq!((attacker[13].energy, defender[13].energy), (42, 42));
   }
 
   #[test]
   fn test_registeel_mirror() {
     // https://pvpoke.com/battle/1500/registeel-22.5-15-2-5-4-4-1/registeel-24.5-1-12-1-4-4-1/22/0-1-2/0-1-2/
-    // let gms = std::fs::read_to_string("data/gamemaster.json").unwrap();
-    // let gm = serde_json::from_str::<GameMaster>(&gms).unwrap();
-    // let mech = Mechanics::try_from(gm).unwrap();
-    let mech = Mechanics::instance();
+    // TODO check against PvPoke's timeline; worked out by hand from the fixture:
+    // - the first Registeel has 123 HP and the second 126, Lock-On does 1 each
+    //   turn
+    // - Focus Blast is the better move, so both bait Flash Cannon at the 75
+    //   energy for it while there are shields, then throw it on turn 45
+    // - the first Registeel has more attack and goes first, but its 80 damage
+    //   leaves the second a few HP, and the 82 coming back KOs it
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
 
     let regi1 = mech.pokemon_instance(
       "REGISTEEL",
-      Level { level: 22, a_half: true },
+      Level::new(22, true).unwrap(),
       15, 2, 5,
       "LOCK_ON_FAST",
       "FOCUS_BLAST",
@@ -494,20 +1284,772 @@ mod tests {
 
     let regi2 = mech.pokemon_instance(
       "REGISTEEL",
-      Level { level: 24, a_half: true },
+      Level::new(24, true).unwrap(),
       1, 12, 1,
       "LOCK_ON_FAST",
       "FOCUS_BLAST",
       Some("FLASH_CANNON")
     ).unwrap();
 
-    println!("{:?}", regi1.type_effectiveness(&regi1.charged_move1));
+    let mut battle = Battle::new(&mech.combat_settings, &mech.stat_stages, regi1, regi2, Shields::Two, Shields::Two);
+    let turns: Vec<TurnStateDTO> = battle.by_ref().collect();
+    assert_eq!(turns.len(), 46);
+    assert!(matches!(battle.result().unwrap().state, BattleState::Loss));
+
+    let (regi1, regi2) = sides(&turns);
+    for side in [&regi1, &regi2] {
+      assert_eq!(turns_of(side, "uses charged move FLASH_CANNON"), vec![15, 30]);
+      assert_eq!(turns_of(side, "uses charged move FOCUS_BLAST"), vec![45]);
+      assert_eq!(turns_of(side, "uses fast move LOCK_ON_FAST").len(), 43);
+      assert_eq!(side.iter().map(|p| p.shields).collect::<Vec<_>>(), [vec![2; 16], vec![1; 15], vec![0; 15]].concat());
+    }
+    // Up to the first shield only: shielded hits do no damage here, but 1 in PvPoke
+    assert_eq!(health_at(&regi1, &[0, 15]), vec![123, 108]);
+    assert_eq!(health_at(&regi2, &[0, 15]), vec![126, 111]);
+  }
+
+  #[test]
+  fn test_battle_in_league() {
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
+    let great = League::new("great", Some(1500));
 
-    let battle = Battle::new(regi1, regi2, Shields::Two, Shields::Two);
+    let registeel = || mech.pokemon_instance(
+      "REGISTEEL",
+      Level::new(22, true).unwrap(),
+      15, 2, 5,
+      "LOCK_ON_FAST",
+      "FOCUS_BLAST",
+      Some("FLASH_CANNON")
+    ).unwrap();
 
-    let v: Vec<_> = battle.collect();
-    for (i, turn) in v.iter().enumerate() {
-      println!("{:>4}\n{}", i, turn);
+    let maxed_registeel = mech.pokemon_instance(
+      "REGISTEEL",
+      Level::new(40, false).unwrap(),
+      15, 15, 15,
+      "LOCK_ON_FAST",
+      "FOCUS_BLAST",
+      Some("FLASH_CANNON")
+    ).unwrap();
+
+    assert!(Battle::in_league(
+      &great, &mech.combat_settings, &mech.stat_stages, registeel(), registeel(), Shields::Two, Shields::Two
+    ).is_ok());
+    assert!(Battle::in_league(
+      &great, &mech.combat_settings, &mech.stat_stages, registeel(), maxed_registeel, Shields::Two, Shields::Two
+    ).is_err());
+  }
+
+  #[test]
+  fn test_fast_move_durations() {
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
+
+    // Confusion takes 4 turns, Lock-On 1
+    let meditite = mech.pokemon_instance(
+      "MEDITITE",
+      Level::new(40, false).unwrap(),
+      15, 15, 15,
+      "CONFUSION_FAST",
+      "ICE_PUNCH",
+      Some("POWER_UP_PUNCH"),
+    ).unwrap();
+    let registeel = mech.pokemon_instance(
+      "REGISTEEL",
+      Level::new(22, true).unwrap(),
+      15, 2, 5,
+      "LOCK_ON_FAST",
+      "FOCUS_BLAST",
+      Some("FLASH_CANNON"),
+    ).unwrap();
+
+    let battle = Battle::new(&mech.combat_settings, &mech.stat_stages, meditite, registeel, Shields::Two, Shields::Two);
+    let turns: Vec<TurnStateDTO> = battle.collect();
+    let (meditite_actions, registeel_actions): (Vec<_>, Vec<_>) =
+      turns.iter().map(|t| (t.attacker.action.clone(), t.defender.action.clone())).unzip();
+
+    // Power-Up Punch is the better move against Registeel, 3 Confusions pay for it
+    assert_eq!(registered(&meditite_actions), (vec![3, 7, 11], Some(12)));
+    assert!(meditite_actions[..3].iter().all(|a| a == "waits"));
+    // Flash Cannon is the better move against Meditite, 14 Lock-Ons pay for it
+    assert_eq!(registered(&registeel_actions), ((0..14).collect(), Some(14)));
+
+    // Confusion only lands on the turn it registers
+    for i in 0..12 {
+      let (before, after) = (turns[i].defender.health, turns[i + 1].defender.health);
+      assert_eq!(before != after, [3, 7, 11].contains(&i), "turn {}", i);
+    }
+  }
+
+  #[test]
+  fn test_fast_move_timelines() {
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
+    let mirror = |species, fast_move, charged_move1, charged_move2| {
+      let instance = || mech.pokemon_instance(
+        species,
+        Level::new(20, false).unwrap(),
+        15, 15, 15,
+        fast_move,
+        charged_move1,
+        Some(charged_move2),
+      ).unwrap();
+      Battle::new(&mech.combat_settings, &mech.stat_stages, instance(), instance(), Shields::Two, Shields::Two)
+    };
+
+    // (battle, fast move duration, fast moves before the first charged move).
+    // Both sides wait for the energy of their better move before baiting.
+    let cases = vec![
+      // 42 energy for Ice Punch
+      (mirror("MEDITITE", "COUNTER_FAST", "ICE_PUNCH", "POWER_UP_PUNCH"), 2, 6),
+      // 75 energy for Focus Blast
+      (mirror("REGISTEEL", "LOCK_ON_FAST", "FOCUS_BLAST", "FLASH_CANNON"), 1, 15),
+      // 60 energy for Dragon Pulse
+      (mirror("ALTARIA", "DRAGON_BREATH_FAST", "SKY_ATTACK", "DRAGON_PULSE"), 1, 20),
+      // 36 energy for Mud Bomb
+      (mirror("WHISCASH", "MUD_SHOT_FAST", "BLIZZARD", "MUD_BOMB"), 2, 4),
+      // 35 energy for Leaf Blade
+      (mirror("VICTREEBEL", "RAZOR_LEAF_FAST", "LEAF_BLADE", "ACID_SPRAY"), 2, 5),
+      // 48 energy for Ice Punch
+      (mirror("MEDITITE", "CONFUSION_FAST", "ICE_PUNCH", "POWER_UP_PUNCH"), 4, 4),
+    ];
+    for (battle, duration, count) in cases {
+      let actions = timeline(battle);
+      let (attacker, defender): (Vec<_>, Vec<_>) = actions.into_iter().unzip();
+      assert_eq!(attacker, defender);
+
+      let (fast, charged) = registered(&attacker);
+      let expected: Vec<usize> = (1..=count).map(|n| n * duration - 1).collect();
+      assert_eq!(fast, expected, "{:?}", attacker);
+      assert_eq!(charged, Some(count * duration));
     }
   }
+
+  #[test]
+  fn test_altaria_vs_registeel_timeline() {
+    // PvPoke's Altaria 28 0/14/15 vs Registeel 22.5 15/2/5, two shields each
+    // TODO check against PvPoke's timeline; worked out by hand from the fixture:
+    // - Dragon Breath and Lock-On register every turn, for 3 and 5 energy
+    // - Altaria throws Sky Attack (45) over Dragon Pulse, after 15 Dragon Breaths
+    // - Registeel throws Flash Cannon (70) over Focus Blast, after 14 Lock-Ons
+    // - each side shields the first two, and the third leaves Registeel to
+    //   Dragon Breath
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
+    let altaria = mech.pokemon_instance(
+      "ALTARIA",
+      Level::new(28, false).unwrap(),
+      0, 14, 15,
+      "DRAGON_BREATH_FAST",
+      "SKY_ATTACK",
+      Some("DRAGON_PULSE"),
+    ).unwrap();
+    let registeel = mech.pokemon_instance(
+      "REGISTEEL",
+      Level::new(22, true).unwrap(),
+      15, 2, 5,
+      "LOCK_ON_FAST",
+      "FOCUS_BLAST",
+      Some("FLASH_CANNON"),
+    ).unwrap();
+
+    let mut battle = Battle::new(&mech.combat_settings, &mech.stat_stages, altaria, registeel, Shields::Two, Shields::Two);
+    let turns: Vec<TurnStateDTO> = battle.by_ref().collect();
+    assert!(matches!(battle.result().unwrap().state, BattleState::Win));
+
+    let (altaria, registeel) = sides(&turns[..48]);
+    let fast_except = |charged: &[usize]| -> Vec<usize> { (0..48).filter(|t| !charged.contains(t)).collect() };
+
+    assert_eq!(turns_of(&altaria, "uses charged move SKY_ATTACK"), vec![15, 31, 47]);
+    assert_eq!(turns_of(&altaria, "uses fast move DRAGON_BREATH_FAST"), fast_except(&[15, 31, 47]));
+    assert_eq!(turns_of(&registeel, "uses charged move FLASH_CANNON"), vec![14, 29, 44]);
+    assert_eq!(turns_of(&registeel, "uses fast move LOCK_ON_FAST"), fast_except(&[14, 29, 44]));
+
+    let shields = |side: &[&PokemonStateDTO], at: &[usize]| -> Vec<i32> { at.iter().map(|t| side[*t].shields).collect() };
+    assert_eq!(shields(&altaria, &[14, 15, 29, 30]), vec![2, 1, 1, 0]);
+    assert_eq!(shields(&registeel, &[15, 16, 31, 32]), vec![2, 1, 1, 0]);
+  }
+
+  #[test]
+  fn test_energy_cap() {
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
+    let registeel = || mech.pokemon_instance(
+      "REGISTEEL",
+      Level::new(22, true).unwrap(),
+      15, 2, 5,
+      "LOCK_ON_FAST",
+      "FOCUS_BLAST",
+      Some("FLASH_CANNON"),
+    ).unwrap();
+
+    let mut battle = Battle::new(&mech.combat_settings, &mech.stat_stages, registeel(), registeel(), Shields::Two, Shields::Two);
+    assert!(battle.result().is_none());
+    let turns: Vec<TurnStateDTO> = battle.by_ref().collect();
+    let max_energy = mech.combat_settings.max_energy;
+    assert!(turns.iter().all(|t| t.attacker.energy <= max_energy && t.defender.energy <= max_energy));
+    let result = battle.result().unwrap();
+    assert_eq!(result.turns as usize, turns.len());
+    assert_eq!(result.wasted_energy, (0, 0));
+
+    // Focus Blast costs 75, so with a cap of 72 Registeel keeps using Lock-On
+    // and loses what goes past the cap
+    let capped = CombatSettings { max_energy: 72, ..mech.combat_settings };
+    let mut battle = Battle::new(&capped, &mech.stat_stages, registeel(), registeel(), Shields::Two, Shields::Two);
+    let turns: Vec<TurnStateDTO> = battle.by_ref().collect();
+    assert!(turns.iter().all(|t| t.attacker.action.starts_with("uses fast move")));
+    assert!(turns.iter().all(|t| t.attacker.energy <= 72));
+    let result = battle.result().unwrap();
+    assert!(matches!(result.state, BattleState::Draw));
+    let lock_ons = turns.len() as i16;
+    assert_eq!(result.wasted_energy, (3 + 5 * (lock_ons - 15), 3 + 5 * (lock_ons - 15)));
+  }
+
+  #[test]
+  fn test_stat_stages() {
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
+    let instance = |species, fast_move, charged_move| mech.pokemon_instance(
+      species,
+      Level::new(20, false).unwrap(),
+      15, 15, 15,
+      fast_move,
+      charged_move,
+      None,
+    ).unwrap();
+    let battle = |attacker, defender| {
+      Battle::new(&mech.combat_settings, &mech.stat_stages, attacker, defender, Shields::One, Shields::One)
+    };
+    // Stages of both sides on the turn after the attacker's first charged move
+    let after_first_charged = |battle: Battle| {
+      let turns: Vec<TurnStateDTO> = battle.collect();
+      let i = turns.iter().position(|t| t.attacker.action.starts_with("uses charged move")).unwrap();
+      let (a, d) = (&turns[i + 1].attacker, &turns[i + 1].defender);
+      ((a.attack_stage, a.defense_stage), (d.attack_stage, d.defense_stage))
+    };
+
+    // Power-Up Punch raises the user's attack, also when shielded
+    let meditite = || instance("MEDITITE", "COUNTER_FAST", "POWER_UP_PUNCH");
+    let registeel = || instance("REGISTEEL", "LOCK_ON_FAST", "FLASH_CANNON");
+    assert_eq!(after_first_charged(battle(meditite(), registeel())), ((1., 0.), (0., 0.)));
+
+    // Close Combat lowers the user's defense
+    let machamp = instance("MACHAMP", "COUNTER_FAST", "CLOSE_COMBAT");
+    assert_eq!(after_first_charged(battle(machamp, registeel())), ((0., -2.), (0., 0.)));
+
+    // Acid Spray lowers the target's defense
+    let victreebel = instance("VICTREEBEL", "RAZOR_LEAF_FAST", "ACID_SPRAY");
+    assert_eq!(after_first_charged(battle(victreebel, registeel())), ((0., 0.), (0., -2.)));
+
+    // Boosted attack shows up in fast move damage
+    let turns: Vec<TurnStateDTO> = battle(meditite(), registeel()).collect();
+    let damage_taken: Vec<(f64, i16)> = turns
+      .windows(2)
+      .filter(|w| w[0].attacker.action.starts_with("uses fast move") && w[0].defender.shields == 0)
+      .map(|w| (w[0].attacker.attack_stage, w[0].defender.health - w[1].defender.health))
+      .filter(|(_, d)| *d > 0)
+      .collect();
+    let at_stage = |stage: f64| damage_taken.iter().find(|(s, _)| *s == stage).map(|(_, d)| *d);
+    assert!(at_stage(2.).unwrap() > at_stage(1.).unwrap());
+  }
+
+  #[test]
+  fn test_buff_procs() {
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
+    let registeel = || mech.pokemon_instance(
+      "REGISTEEL",
+      Level::new(20, false).unwrap(),
+      15, 15, 15,
+      "LOCK_ON_FAST",
+      "FLASH_CANNON",
+      None,
+    ).unwrap();
+    // Power-Up Punch that only works half the time
+    let meditite = || {
+      let base = mech.pokemon_instance(
+        "MEDITITE",
+        Level::new(20, false).unwrap(),
+        15, 15, 15,
+        "COUNTER_FAST",
+        "POWER_UP_PUNCH",
+        None,
+      ).unwrap();
+      let mut punch = (*base.charged_move1).clone();
+      punch.buffs = punch.buffs.map(|b| MoveBuffs { chance: 0.5, ..b });
+      let punch = std::sync::Arc::new(punch);
+      PokemonInstance::new(
+        base.pokemon.clone(),
+        base.level(),
+        mech.cp_multiplier(&base.level()).unwrap(),
+        base.ivs().0, base.ivs().1, base.ivs().2,
+        base.fast_move.clone(),
+        punch.clone(),
+        punch,
+      )
+    };
+    let stages = |procs| {
+      Battle::new(&mech.combat_settings, &mech.stat_stages, meditite(), registeel(), Shields::Two, Shields::Two)
+        .with_buff_procs(procs)
+        .map(|t| (t.attacker.action, t.attacker.attack_stage))
+        .collect::<Vec<_>>()
+    };
+    // Attack stage on the last turn, and the Power-Up Punches thrown before it
+    let outcome = |procs| {
+      let turns = stages(procs);
+      let (last, before) = turns.split_last().unwrap();
+      let punches = before.iter().filter(|(a, _)| a == "uses charged move POWER_UP_PUNCH").count();
+      (last.1, punches as f64)
+    };
+
+    let (stage, punches) = outcome(BuffProcs::Never);
+    assert!(punches >= 2.);
+    assert_eq!(stage, 0.);
+    let (stage, punches) = outcome(BuffProcs::Always);
+    assert_eq!(stage, f64::min(punches, 4.));
+    let (stage, punches) = outcome(BuffProcs::ExpectedValue);
+    assert_eq!(stage, f64::min(punches * 0.5, 4.));
+
+    // The same seed plays out the same way, and procs are all or nothing
+    let seeded: Vec<_> = (0..8).map(|seed| stages(BuffProcs::Random(seed))).collect();
+    assert_eq!(seeded[3], stages(BuffProcs::Random(3)));
+    assert!(seeded.iter().all(|s| s.iter().all(|(_, stage)| stage.fract() == 0.)));
+    assert!(seeded.iter().any(|s| s != &seeded[0]));
+  }
+
+  #[test]
+  fn test_shield_policies() {
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
+    let registeel = |level| mech.pokemon_instance(
+      "REGISTEEL",
+      Level::new(level, false).unwrap(),
+      15, 2, 5,
+      "LOCK_ON_FAST",
+      "FOCUS_BLAST",
+      Some("FLASH_CANNON"),
+    ).unwrap();
+    // The second side's shields, and whether it took the hit, on the turn
+    // after each charged move of the first side
+    let shielding_at = |level, policies: Box<dyn Fn(Battle) -> Battle>| {
+      let battle = Battle::new(
+        &mech.combat_settings, &mech.stat_stages, registeel(22), registeel(level), Shields::Two, Shields::Two,
+      );
+      let turns: Vec<TurnStateDTO> = policies(battle).collect();
+      turns
+        .windows(2)
+        .filter(|w| w[0].attacker.action.starts_with("uses charged move"))
+        .map(|w| (w[1].defender.shields, w[0].defender.health - w[1].defender.health > 5))
+        .collect::<Vec<_>>()
+    };
+    let shielding = |policies| shielding_at(22, policies);
+
+    // Shields go first, then the hits land
+    let always = shielding(Box::new(|b| b));
+    assert_eq!(&always[..2], &[(1, false), (0, false)]);
+    assert!(always[2..].iter().all(|&h| h == (0, true)));
+    assert_eq!(always, shielding(Box::new(|b| b.with_shield_policies(AlwaysShield, AlwaysShield))));
+
+    let never = shielding(Box::new(|b| b.with_shield_policies(AlwaysShield, NeverShield)));
+    assert!(never.iter().all(|&h| h == (2, true)));
+
+    let saving = shielding(Box::new(|b| b.with_shield_policies(AlwaysShield, SaveLastShield(AlwaysShield))));
+    assert_eq!(saving[0], (1, false));
+    assert!(saving[1..].iter().all(|&h| h == (1, true)));
+
+    // Only hits that would KO get shielded, against a much weaker Registeel
+    let lethal = shielding_at(15, Box::new(|b| b.with_shield_policies(AlwaysShield, ShieldThreshold { hp_fraction: 1. })));
+    let first_shield = lethal.iter().position(|&(shields, _)| shields < 2).unwrap();
+    assert!(first_shield > 0);
+    assert!(lethal[..first_shield].iter().all(|&h| h == (2, true)));
+    assert_eq!(lethal[first_shield], (1, false));
+    // And a low threshold shields everything
+    let low = shielding(Box::new(|b| b.with_shield_policies(AlwaysShield, ShieldThreshold { hp_fraction: 0.01 })));
+    assert_eq!(low, always);
+
+    let incoming = IncomingCharged { damage: 40, health: 100, max_health: 150, shields: Shields::One };
+    assert!(!incoming.is_lethal());
+    assert!(ShieldThreshold { hp_fraction: 0.25 }.shield(&incoming));
+    assert!(!ShieldThreshold { hp_fraction: 0.3 }.shield(&incoming));
+    assert!(!SaveLastShield(AlwaysShield).shield(&incoming));
+    assert!(ShieldThreshold { hp_fraction: 0.3 }.shield(&IncomingCharged { damage: 100, ..incoming }));
+  }
+
+  #[test]
+  fn test_default_strategy() {
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
+    let registeel = mech.pokemon_instance(
+      "REGISTEEL",
+      Level::new(22, true).unwrap(),
+      15, 2, 5,
+      "LOCK_ON_FAST",
+      "FOCUS_BLAST",
+      Some("FLASH_CANNON"),
+    ).unwrap();
+    let state = |health, energy, shields| PokemonState {
+      health,
+      energy,
+      shields,
+      ..PokemonState::new(&registeel, Shields::Two)
+    };
+    let turn = |state| TurnState::new(
+      state, &registeel, &mech.combat_settings, &mech.stat_stages, &AlwaysShield, &DefaultStrategy,
+    );
+    let decide = |me, opponent| DefaultStrategy.decide(&turn(me), &turn(opponent));
+
+    let (me, opponent) = (turn(state(100, 0, Shields::Two)), turn(state(100, 0, Shields::Two)));
+    let fast = me.fast_damage(&opponent);
+    let focus_blast = me.charged_damage(ChargedChoice::Main, &opponent);
+    let flash_cannon = me.charged_damage(ChargedChoice::Other, &opponent);
+    assert!(fast < flash_cannon && flash_cannon < focus_blast);
+
+    // Not enough energy for either move
+    assert_eq!(decide(state(100, 69, Shields::Two), state(100, 0, Shields::Two)), Action::Fast);
+    // Enough for Flash Cannon, but Focus Blast is the better move: wait for it
+    assert_eq!(decide(state(100, 70, Shields::None), state(100, 0, Shields::None)), Action::Fast);
+    // Bait with Flash Cannon only while the opponent has shields
+    assert_eq!(decide(state(100, 75, Shields::Two), state(100, 0, Shields::One)), Action::Charged(ChargedChoice::Other));
+    assert_eq!(decide(state(100, 75, Shields::Two), state(200, 0, Shields::None)), Action::Charged(ChargedChoice::Main));
+
+    // Flash Cannon KOs an unshielded opponent, so it goes without waiting
+    let low = flash_cannon;
+    assert_eq!(decide(state(100, 70, Shields::Two), state(low, 0, Shields::None)), Action::Charged(ChargedChoice::Other));
+    assert_eq!(decide(state(100, 75, Shields::Two), state(low, 0, Shields::None)), Action::Charged(ChargedChoice::Other));
+    // ...but not through a shield
+    assert_eq!(decide(state(100, 70, Shields::Two), state(low, 0, Shields::One)), Action::Fast);
+
+    // A fast move KO beats any charged move
+    assert_eq!(decide(state(100, 100, Shields::Two), state(fast, 0, Shields::None)), Action::Fast);
+  }
+
+  #[test]
+  fn test_register_charged_needs_energy() {
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
+    let registeel = mech.pokemon_instance(
+      "REGISTEEL",
+      Level::new(22, true).unwrap(),
+      15, 2, 5,
+      "LOCK_ON_FAST",
+      "FOCUS_BLAST",
+      Some("FLASH_CANNON"),
+    ).unwrap();
+    let turn = |energy| TurnState::new(
+      PokemonState { energy, ..PokemonState::new(&registeel, Shields::Two) },
+      &registeel, &mech.combat_settings, &mech.stat_stages, &AlwaysShield, &DefaultStrategy,
+    );
+    let mut roller = BuffRoller::new(BuffProcs::Never);
+
+    // Focus Blast costs 75: nothing is spent, and there's nothing to shield
+    let (mut attacker, mut defender) = (turn(70), turn(0));
+    let hit = attacker.register_charged(ChargedChoice::Main, &defender, &mut roller);
+    assert!(hit.is_none());
+    assert_eq!(attacker.state.energy, 70);
+    assert!(!defender.defend_charged(hit));
+    assert_eq!(defender.state.shields, Shields::Two);
+
+    let mut attacker = turn(75);
+    let hit = attacker.register_charged(ChargedChoice::Main, &defender, &mut roller);
+    assert!(hit.is_some());
+    assert_eq!(attacker.state.energy, 0);
+    defender.defend_charged(hit);
+    assert_eq!(defender.state.shields, Shields::One);
+  }
+
+  #[test]
+  fn test_custom_strategies() {
+    struct Passive;
+    impl BattleStrategy for Passive {
+      fn decide(&self, _: &TurnState, _: &TurnState) -> Action {
+        Action::Wait
+      }
+    }
+
+    // Focus Blast whenever asked, which falls back to Lock-On until it's paid for
+    struct FocusBlastOnly;
+    impl BattleStrategy for FocusBlastOnly {
+      fn decide(&self, _: &TurnState, _: &TurnState) -> Action {
+        Action::Charged(ChargedChoice::Main)
+      }
+    }
+
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
+    let registeel = || mech.pokemon_instance(
+      "REGISTEEL",
+      Level::new(22, true).unwrap(),
+      15, 2, 5,
+      "LOCK_ON_FAST",
+      "FOCUS_BLAST",
+      Some("FLASH_CANNON"),
+    ).unwrap();
+    let battle = || Battle::new(
+      &mech.combat_settings, &mech.stat_stages, registeel(), registeel(), Shields::Two, Shields::Two,
+    );
+
+    let mut passive = battle().with_strategies(Passive, DefaultStrategy);
+    let turns: Vec<TurnStateDTO> = passive.by_ref().collect();
+    assert!(turns.iter().all(|t| t.attacker.action == "does nothing"));
+    assert!(turns.iter().all(|t| t.attacker.energy == 0));
+    assert!(matches!(passive.result().unwrap().state, BattleState::Loss));
+
+    let (focus_blast, _): (Vec<_>, Vec<_>) = timeline(battle().with_strategies(FocusBlastOnly, DefaultStrategy))
+      .into_iter()
+      .unzip();
+    let (fast, charged) = registered(&focus_blast);
+    assert_eq!((fast, charged), ((0..15).collect(), Some(15)));
+    assert!(focus_blast
+      .iter()
+      .filter(|a| a.starts_with("uses charged move"))
+      .all(|a| a == "uses charged move FOCUS_BLAST"));
+  }
+
+  // Level 20 15/15/15 teams, by species
+  fn team(mech: &Mechanics, species: &[&str]) -> Vec<PokemonInstance> {
+    let moves = |species| match species {
+      "REGISTEEL" => ("LOCK_ON_FAST", "FOCUS_BLAST", "FLASH_CANNON"),
+      "ALTARIA" => ("DRAGON_BREATH_FAST", "SKY_ATTACK", "DRAGON_PULSE"),
+      "WHISCASH" => ("MUD_SHOT_FAST", "BLIZZARD", "MUD_BOMB"),
+      "VICTREEBEL" => ("RAZOR_LEAF_FAST", "LEAF_BLADE", "ACID_SPRAY"),
+      "MEDITITE" => ("COUNTER_FAST", "ICE_PUNCH", "POWER_UP_PUNCH"),
+      _ => ("COUNTER_FAST", "DYNAMIC_PUNCH", "CLOSE_COMBAT"),
+    };
+    species
+      .iter()
+      .map(|s| {
+        let (fast_move, charged_move1, charged_move2) = moves(*s);
+        mech.pokemon_instance(
+          *s,
+          Level::new(20, false).unwrap(),
+          15, 15, 15,
+          fast_move,
+          charged_move1,
+          Some(charged_move2),
+        ).unwrap()
+      })
+      .collect()
+  }
+
+  #[test]
+  fn test_team_battle() {
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
+    let team1 = team(&mech, &["REGISTEEL", "ALTARIA", "VICTREEBEL"]);
+    let team2 = team(&mech, &["WHISCASH", "MEDITITE", "MACHAMP"]);
+
+    let mut battle = TeamBattle::new(&mech.combat_settings, &mech.stat_stages, team1.clone(), team2.clone()).unwrap();
+    let turns: Vec<TurnStateDTO> = battle.by_ref().collect();
+    let result = battle.result().unwrap();
+    assert_eq!(result.turns as usize, turns.len());
+
+    // Someone's whole team went down, in order, and the other team has someone left
+    let (faints1, faints2) = &result.faints;
+    let (loser, winner) = match result.state {
+      BattleState::Win => (faints2, faints1),
+      BattleState::Loss => (faints1, faints2),
+      _ => panic!("Expected a winner"),
+    };
+    assert_eq!(loser.iter().map(|f| f.slot).collect::<Vec<_>>(), vec![0, 1, 2]);
+    assert!(winner.len() < 3);
+    for faint in faints1.iter().chain(faints2.iter()) {
+      let side = if faints1.contains(faint) { &turns[faint.turn as usize].attacker } else { &turns[faint.turn as usize].defender };
+      assert_eq!(side.pokemon_id, faint.pokemon_id);
+      // The replacement comes in on the next turn, if there's one
+      if let Some(next) = turns.get(faint.turn as usize + 1) {
+        let next = if faints1.contains(faint) { &next.attacker } else { &next.defender };
+        assert_ne!(next.pokemon_id, faint.pokemon_id);
+      }
+    }
+    // Shields are shared across the team, replacements don't bring new ones
+    assert!(turns.windows(2).all(|t| t[0].attacker.shields >= t[1].attacker.shields));
+    assert!(turns.windows(2).all(|t| t[0].defender.shields >= t[1].defender.shields));
+    assert!(turns.iter().all(|t| !t.attacker.action.starts_with("switches")));
+
+    // Picking the last Pokémon on the bench instead
+    struct Last;
+    impl SwitchPolicy for Last {
+      fn switch(&self, _: &TurnState, _: &[TurnState], _: &TurnState) -> Option<usize> {
+        None
+      }
+      fn replace(&self, bench: &[TurnState], _: &TurnState) -> usize {
+        bench.len() - 1
+      }
+    }
+    let mut battle = TeamBattle::new(&mech.combat_settings, &mech.stat_stages, team1.clone(), team2.clone())
+      .unwrap()
+      .with_switch_policies(Last, Last);
+    battle.by_ref().for_each(drop);
+    let result = battle.result().unwrap();
+    let slots = |faints: &[Faint]| faints.iter().map(|f| f.slot).collect::<Vec<_>>();
+    let lost = if matches!(result.state, BattleState::Win) { &result.faints.1 } else { &result.faints.0 };
+    assert_eq!(slots(lost), vec![0, 2, 1]);
+
+    let mut unshielded = TeamBattle::new(&mech.combat_settings, &mech.stat_stages, team1.clone(), team2.clone())
+      .unwrap()
+      .with_shields(Shields::None, Shields::One);
+    let first = unshielded.next().unwrap();
+    assert_eq!((first.attacker.shields, first.defender.shields), (0, 1));
+
+    assert!(TeamBattle::new(&mech.combat_settings, &mech.stat_stages, vec![], team2.clone()).is_err());
+    let four = team(&mech, &["REGISTEEL", "ALTARIA", "VICTREEBEL", "MEDITITE"]);
+    assert!(TeamBattle::new(&mech.combat_settings, &mech.stat_stages, team1.clone(), four).is_err());
+    let great = League::new("great", Some(1500));
+    let maxed = mech.pokemon_instance(
+      "REGISTEEL",
+      Level::new(40, false).unwrap(),
+      15, 15, 15,
+      "LOCK_ON_FAST",
+      "FOCUS_BLAST",
+      Some("FLASH_CANNON"),
+    ).unwrap();
+    assert!(TeamBattle::in_league(&great, &mech.combat_settings, &mech.stat_stages, vec![maxed], team2).is_err());
+  }
+
+  #[test]
+  fn test_team_switching() {
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
+    assert_eq!(mech.combat_settings.switch_cooldown_turns(), 120);
+
+    // Switches to the next Pokémon as soon as the active one has some energy
+    struct Hop;
+    impl SwitchPolicy for Hop {
+      fn switch(&self, active: &TurnState, _: &[TurnState], _: &TurnState) -> Option<usize> {
+        if active.energy() >= 20 { Some(0) } else { None }
+      }
+      fn replace(&self, _: &[TurnState], _: &TurnState) -> usize {
+        0
+      }
+    }
+
+    struct FastOnly;
+    impl BattleStrategy for FastOnly {
+      fn decide(&self, _: &TurnState, _: &TurnState) -> Action {
+        Action::Fast
+      }
+    }
+
+    let team1 = team(&mech, &["REGISTEEL", "ALTARIA"]);
+    let team2 = team(&mech, &["REGISTEEL", "REGISTEEL", "REGISTEEL"]);
+    // A 10 second timer
+    let settings = CombatSettings { switch_cooldown: Some(10.), ..mech.combat_settings };
+    let battle = TeamBattle::new(&settings, &mech.stat_stages, team1, team2)
+      .unwrap()
+      .with_switch_policies(Hop, InOrder)
+      .with_strategies(FastOnly, FastOnly);
+    let turns: Vec<TurnStateDTO> = battle.collect();
+
+    let switches: Vec<usize> = turns
+      .iter()
+      .enumerate()
+      .filter(|(_, t)| t.attacker.action.starts_with("switches"))
+      .map(|(i, _)| i)
+      .collect();
+    assert!(switches.len() >= 2, "{:?}", switches);
+    // The switch timer keeps switches 20 turns apart
+    for pair in switches.windows(2) {
+      assert!(pair[1] - pair[0] >= 20);
+    }
+
+    // Registeel leaves after its 4th Lock-On, and comes back with the health and
+    // energy it left with
+    let (out, back) = (switches[0], switches[1]);
+    assert_eq!(out, 4);
+    assert_eq!((turns[out].attacker.pokemon_id.as_str(), turns[out].attacker.energy), ("ALTARIA", 0));
+    assert_eq!(turns[back].attacker.pokemon_id, "REGISTEEL");
+    assert_eq!(turns[back].attacker.energy, 20);
+    // Less the Lock-On it took on its last turn in
+    assert_eq!(turns[back].attacker.health, turns[out - 1].attacker.health - 1);
+    assert_eq!(turns[back].attacker.shields, 2);
+  }
+
+  #[test]
+  fn test_team_wasted_energy() {
+    // Switches out once the active Pokémon is at the cap
+    struct Hop;
+    impl SwitchPolicy for Hop {
+      fn switch(&self, active: &TurnState, _: &[TurnState], _: &TurnState) -> Option<usize> {
+        if active.energy() >= 20 { Some(0) } else { None }
+      }
+      fn replace(&self, _: &[TurnState], _: &TurnState) -> usize {
+        0
+      }
+    }
+
+    struct FastOnly;
+    impl BattleStrategy for FastOnly {
+      fn decide(&self, _: &TurnState, _: &TurnState) -> Action {
+        Action::Fast
+      }
+    }
+
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
+    let settings = CombatSettings { max_energy: 20, switch_cooldown: Some(10.), ..mech.combat_settings };
+    let team1 = team(&mech, &["REGISTEEL", "REGISTEEL"]);
+    let team2 = team(&mech, &["REGISTEEL"]);
+    let mut battle = TeamBattle::new(&settings, &mech.stat_stages, team1, team2)
+      .unwrap()
+      .with_switch_policies(Hop, InOrder)
+      .with_strategies(FastOnly, FastOnly);
+    let turns: Vec<TurnStateDTO> = battle.by_ref().collect();
+    let result = battle.result().unwrap();
+
+    // Lock-On brings 5 energy, and whatever doesn't fit under the cap is lost
+    let wasted = |side: Vec<&PokemonStateDTO>| -> i16 {
+      side.iter().filter(|p| p.action.starts_with("uses fast move")).map(|p| 5 - i16::min(5, 20 - p.energy)).sum()
+    };
+    let expected = (
+      wasted(turns.iter().map(|t| &t.attacker).collect()),
+      wasted(turns.iter().map(|t| &t.defender).collect()),
+    );
+    assert_eq!(result.wasted_energy, expected);
+    // The first team switched, so its total spans both of its Pokémon
+    assert!(turns.iter().any(|t| t.attacker.action.starts_with("switches")));
+    assert!(expected.0 > 0 && expected.1 > 0);
+  }
+
+  #[test]
+  fn test_battle_timer() {
+    struct Passive;
+    impl BattleStrategy for Passive {
+      fn decide(&self, _: &TurnState, _: &TurnState) -> Action {
+        Action::Wait
+      }
+    }
+
+    struct FastOnly;
+    impl BattleStrategy for FastOnly {
+      fn decide(&self, _: &TurnState, _: &TurnState) -> Action {
+        Action::Fast
+      }
+    }
+
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
+    assert_eq!(mech.combat_settings.round_turns(), 480);
+    let registeel = || team(&mech, &["REGISTEEL"]).remove(0);
+    let battle = || Battle::new(
+      &mech.combat_settings, &mech.stat_stages, registeel(), registeel(), Shields::Two, Shields::Two,
+    );
+
+    // Nobody attacks, yet the battle ends on the clock
+    let mut stalled = battle().with_strategies(Passive, Passive);
+    assert_eq!(stalled.by_ref().count(), 480);
+    let result = stalled.result().unwrap();
+    assert!(matches!(result.state, BattleState::Timeout(Tiebreak::Draw)));
+    assert_eq!(result.turns, 480);
+    assert!(stalled.next().is_none());
+
+    // More HP left wins
+    let mut chipped = battle().with_strategies(Passive, FastOnly).with_time_limit(10);
+    assert_eq!(chipped.by_ref().count(), 10);
+    assert!(matches!(chipped.result().unwrap().state, BattleState::Timeout(Tiebreak::Loss)));
+
+    let mut instant = battle().with_time_limit(0);
+    assert!(matches!(instant.result().unwrap().state, BattleState::Timeout(Tiebreak::Draw)));
+    assert!(instant.next().is_none());
+
+    // Pokémon left come before HP
+    assert_eq!(Tiebreak::new((2, 10), (1, 200)), Tiebreak::Win);
+    assert_eq!(Tiebreak::new((1, 10), (1, 200)), Tiebreak::Loss);
+    assert_eq!(Tiebreak::new((0, 0), (0, 0)), Tiebreak::Draw);
+
+    let mut teams = TeamBattle::new(
+      &mech.combat_settings,
+      &mech.stat_stages,
+      team(&mech, &["REGISTEEL", "ALTARIA"]),
+      team(&mech, &["REGISTEEL"]),
+    ).unwrap().with_strategies(Passive, Passive);
+    assert_eq!(teams.by_ref().count(), 480);
+    let result = teams.result().unwrap();
+    assert!(matches!(result.state, BattleState::Timeout(Tiebreak::Win)));
+    assert_eq!(result.faints, (vec![], vec![]));
+  }
 }
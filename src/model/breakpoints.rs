@@ -0,0 +1,256 @@
+use crate::error::*;
+use crate::model::league::*;
+use crate::model::mechanics::Mechanics;
+use crate::model::moves::*;
+use crate::model::pokemon::*;
+use crate::ranker::{rank_table, Encounter};
+
+use std::sync::Arc;
+
+// ===================
+// === Breakpoints ===
+// ===================
+
+// A league-legal build: the IV spread at its highest level under the CP cap
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Build {
+  pub atk_iv: Iv,
+  pub def_iv: Iv,
+  pub sta_iv: Iv,
+  pub level: Level,
+  pub attack: f64,
+  pub defense: f64,
+  // Stat product rank in the league
+  pub rank: usize,
+}
+
+// One damage value a move can do against the opponent, the lowest attack
+// stat reaching it, and every build that does exactly that much
+#[derive(Debug, Clone, PartialEq)]
+pub struct Breakpoint {
+  pub move_id: String,
+  pub damage: i16,
+  pub min_attack: f64,
+  pub builds: Vec<Build>,
+}
+
+// One damage value an opponent's move does to the species, the lowest defense
+// stat taking only that much, and every build that takes exactly that much
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bulkpoint {
+  pub move_id: String,
+  pub damage: i16,
+  pub min_defense: f64,
+  pub builds: Vec<Build>,
+}
+
+// Groups builds by the damage they do or take
+fn group_by_damage<F: Fn(&PokemonInstance) -> i16>(
+  instances: &[(Build, PokemonInstance)],
+  damage: F,
+) -> Vec<(i16, Vec<Build>)> {
+  let mut groups: Vec<(i16, Vec<Build>)> = Vec::new();
+  for (build, instance) in instances {
+    let d = damage(instance);
+    match groups.iter_mut().find(|(g, _)| *g == d) {
+      Some((_, builds)) => builds.push(*build),
+      None => groups.push((d, vec![*build])),
+    }
+  }
+  groups
+}
+
+impl Mechanics {
+  // Every build of the species in the league, as instances carrying its first
+  // moves, which don't matter here
+  fn league_builds(&self, species: SpeciesId, league: &League) -> Result<Vec<(Build, PokemonInstance)>, Error> {
+    let pok = self
+      .pokemon(&species)
+      .ok_or_else(|| Error::BoundsError(format!("Could not find pokemon {}", species)))?;
+    let moves = pok.fast_moves.values().next().zip(pok.charged_moves.values().next());
+    let (fast_move, charged_move) = match moves {
+      Some((f, c)) => (f.clone(), c.clone()),
+      None => return Err(Error::ParseError(format!("{} has no moves", pok.id))),
+    };
+
+    rank_table(self, pok.species(), league, &Encounter::WILD)?
+      .into_iter()
+      .map(|e| {
+        let instance = PokemonInstance::new(
+          pok.clone(),
+          e.level,
+          self.cp_multiplier(&e.level)?,
+          e.atk,
+          e.def,
+          e.sta,
+          fast_move.clone(),
+          charged_move.clone(),
+          charged_move.clone(),
+        );
+        let build = Build {
+          atk_iv: e.atk,
+          def_iv: e.def,
+          sta_iv: e.sta,
+          level: e.level,
+          attack: instance.attack(),
+          defense: instance.defense(),
+          rank: e.rank,
+        };
+        Ok((build, instance))
+      })
+      .collect()
+  }
+
+  // For each of the species' moves, sorted by id with fast moves first, the
+  // damage it can do to `opponent` across the league's builds, lowest first
+  pub fn breakpoints<S: Into<SpeciesId>>(
+    &self,
+    species: S,
+    opponent: &PokemonInstance,
+    league: &League,
+  ) -> Result<Vec<Breakpoint>, Error> {
+    let builds = self.league_builds(species.into(), league)?;
+    let pok = match builds.first() {
+      Some((_, instance)) => instance.pokemon.clone(),
+      None => return Ok(Vec::new()),
+    };
+
+    let mut fast_moves: Vec<&Arc<FastMove>> = pok.fast_moves.values().collect();
+    fast_moves.sort_by(|a, b| a.uid.cmp(&b.uid));
+    let mut charged_moves: Vec<&Arc<ChargedMove>> = pok.charged_moves.values().collect();
+    charged_moves.sort_by(|a, b| a.uid.cmp(&b.uid));
+    let moves = fast_moves
+      .iter()
+      .map(|m| (&m.uid, m.as_ref() as &dyn Damage))
+      .chain(charged_moves.iter().map(|m| (&m.uid, m.as_ref() as &dyn Damage)));
+
+    let mut breakpoints = Vec::new();
+    for (move_id, move_) in moves {
+      let mut groups = group_by_damage(&builds, |i| move_.calculate(i, opponent, &self.combat_settings));
+      groups.sort_by_key(|(damage, _)| *damage);
+      breakpoints.extend(groups.into_iter().map(|(damage, builds)| Breakpoint {
+        move_id: move_id.clone(),
+        damage,
+        min_attack: builds.iter().map(|b| b.attack).fold(f64::INFINITY, f64::min),
+        builds,
+      }));
+    }
+    Ok(breakpoints)
+  }
+
+  // For each of the opponent's moves, the damage it does to the species across
+  // the league's builds, highest first
+  pub fn bulkpoints<S: Into<SpeciesId>>(
+    &self,
+    species: S,
+    opponent: &PokemonInstance,
+    league: &League,
+  ) -> Result<Vec<Bulkpoint>, Error> {
+    let builds = self.league_builds(species.into(), league)?;
+
+    let mut moves: Vec<(&String, &dyn Damage)> = vec![
+      (&opponent.fast_move.uid, opponent.fast_move.as_ref()),
+      (&opponent.charged_move1.uid, opponent.charged_move1.as_ref()),
+    ];
+    if opponent.charged_move2.uid != opponent.charged_move1.uid {
+      moves.push((&opponent.charged_move2.uid, opponent.charged_move2.as_ref()));
+    }
+
+    let mut bulkpoints = Vec::new();
+    for (move_id, move_) in moves {
+      let mut groups = group_by_damage(&builds, |i| move_.calculate(opponent, i, &self.combat_settings));
+      groups.sort_by_key(|(damage, _)| std::cmp::Reverse(*damage));
+      bulkpoints.extend(groups.into_iter().map(|(damage, builds)| Bulkpoint {
+        move_id: move_id.clone(),
+        damage,
+        min_defense: builds.iter().map(|b| b.defense).fold(f64::INFINITY, f64::min),
+        builds,
+      }));
+    }
+    Ok(bulkpoints)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::gamemaster::test_fixture;
+
+  #[test]
+  fn test_breakpoints() {
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
+    let great = League::new("great", Some(1500));
+    let registeel = mech.pokemon_instance(
+      "REGISTEEL",
+      Level::new(22, true).unwrap(),
+      15, 2, 5,
+      "LOCK_ON_FAST",
+      "FOCUS_BLAST",
+      Some("FLASH_CANNON"),
+    ).unwrap();
+
+    let breakpoints = mech.breakpoints("MACHAMP", &registeel, &great).unwrap();
+    let of = |id: &str| -> Vec<&Breakpoint> { breakpoints.iter().filter(|b| b.move_id == id).collect() };
+    assert_eq!(breakpoints[0].move_id, "COUNTER_FAST");
+    // No spread gets Counter past 8 damage against this Registeel
+    assert_eq!(of("COUNTER_FAST").len(), 1);
+    assert_eq!(of("COUNTER_FAST")[0].damage, 8);
+
+    let dynamic_punch = of("DYNAMIC_PUNCH");
+    assert_eq!(dynamic_punch.len(), 8);
+    assert_eq!(dynamic_punch.iter().map(|b| b.builds.len()).sum::<usize>(), 4096);
+    for pair in dynamic_punch.windows(2) {
+      assert_eq!(pair[0].damage + 1, pair[1].damage);
+      assert!(pair[0].min_attack < pair[1].min_attack);
+    }
+
+    let ice_punch = of("ICE_PUNCH");
+    assert_eq!(ice_punch.iter().map(|b| b.damage).collect::<Vec<_>>(), vec![17, 18]);
+    // Every listed build does the listed damage
+    for build in &ice_punch[1].builds {
+      let machamp = mech.pokemon_instance(
+        "MACHAMP",
+        build.level,
+        build.atk_iv.value(), build.def_iv.value(), build.sta_iv.value(),
+        "COUNTER_FAST",
+        "ICE_PUNCH",
+        None,
+      ).unwrap();
+      assert_eq!(machamp.attack(), build.attack);
+      assert!(machamp.attack() >= ice_punch[1].min_attack);
+      assert_eq!(machamp.charged_move1.calculate(&machamp, &registeel, &mech.combat_settings), 18);
+    }
+
+    // Forms bring their own moves
+    let alolan = mech.breakpoints(("MAROWAK", "ALOLA"), &registeel, &great).unwrap();
+    assert!(alolan.iter().any(|b| b.move_id == "SHADOW_BONE"));
+    assert!(alolan.iter().all(|b| b.move_id != "BONE_CLUB"));
+
+    assert!(mech.breakpoints("MISSINGNO", &registeel, &great).is_err());
+  }
+
+  #[test]
+  fn test_bulkpoints() {
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
+    let great = League::new("great", Some(1500));
+    let machamp = mech.pokemon_instance(
+      "MACHAMP",
+      Level::new(18, false).unwrap(),
+      4, 15, 15,
+      "COUNTER_FAST",
+      "DYNAMIC_PUNCH",
+      None,
+    ).unwrap();
+
+    let bulkpoints = mech.bulkpoints("REGISTEEL", &machamp, &great).unwrap();
+    // Dynamic Punch is both charged moves
+    assert!(bulkpoints.iter().all(|b| b.move_id == "COUNTER_FAST" || b.move_id == "DYNAMIC_PUNCH"));
+    let counter: Vec<&Bulkpoint> = bulkpoints.iter().filter(|b| b.move_id == "COUNTER_FAST").collect();
+    assert!(counter.len() > 1);
+    assert_eq!(counter.iter().map(|b| b.builds.len()).sum::<usize>(), 4096);
+    for pair in counter.windows(2) {
+      assert!(pair[0].damage > pair[1].damage);
+      assert!(pair[0].min_defense < pair[1].min_defense);
+    }
+  }
+}
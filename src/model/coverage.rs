@@ -0,0 +1,268 @@
+use crate::model::mechanics::Mechanics;
+use crate::model::pokemon::*;
+use crate::model::{Type, TYPE_ORDERING};
+
+// ================
+// === Coverage ===
+// ================
+
+// A defending type, or pair of types, as (type1, type2)
+pub type TypeCombination = (Type, Option<Type>);
+
+// Every single and dual type a defender can have, in TYPE_ORDERING
+pub fn type_combinations() -> Vec<TypeCombination> {
+  let mut combinations = Vec::new();
+  for (i, t1) in TYPE_ORDERING.iter().enumerate() {
+    combinations.push((*t1, None));
+    for t2 in &TYPE_ORDERING[i + 1..] {
+      combinations.push((*t1, Some(*t2)));
+    }
+  }
+  combinations
+}
+
+fn types_of((t1, t2): &TypeCombination) -> Vec<Type> {
+  std::iter::once(*t1).chain(*t2).collect()
+}
+
+// Compare with a little slack, since e.g. 1.6 * 0.625 is only about 1
+fn is_super_effective(multiplier: f64) -> bool {
+  multiplier > 1. + 1e-6
+}
+
+fn is_resisted(multiplier: f64) -> bool {
+  multiplier < 1. - 1e-6
+}
+
+// Defending type combinations a move hits for more, the same or less than 1x
+#[derive(Debug, Clone, PartialEq)]
+pub struct MoveCoverage {
+  pub move_id: String,
+  pub type_: Type,
+  pub super_effective: Vec<TypeCombination>,
+  pub neutral: Vec<TypeCombination>,
+  pub resisted: Vec<TypeCombination>,
+}
+
+// Attacking types against a species, with their multipliers
+#[derive(Debug, Clone, PartialEq)]
+pub struct Weaknesses {
+  pub weak_to: Vec<(Type, f64)>,
+  pub neutral: Vec<Type>,
+  pub resists: Vec<(Type, f64)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Coverage {
+  pub pokemon_id: String,
+  pub moves: Vec<MoveCoverage>,
+  pub weaknesses: Weaknesses,
+}
+
+impl Coverage {
+  pub fn hits_super_effectively(&self, combination: &TypeCombination) -> bool {
+    self.moves.iter().any(|m| m.super_effective.contains(combination))
+  }
+}
+
+// Across a team: defending types none of its moves hit super effectively, and
+// attacking types no member resists
+#[derive(Debug, Clone, PartialEq)]
+pub struct TeamCoverage {
+  pub members: Vec<Coverage>,
+  pub uncovered: Vec<TypeCombination>,
+  pub unresisted: Vec<Type>,
+  // How many members are weak to each attacking type, most shared first
+  pub shared_weaknesses: Vec<(Type, usize)>,
+}
+
+impl Mechanics {
+  pub fn move_coverage(&self, move_id: &str, type_: Type) -> MoveCoverage {
+    let mut coverage = MoveCoverage {
+      move_id: move_id.to_owned(),
+      type_,
+      super_effective: Vec::new(),
+      neutral: Vec::new(),
+      resisted: Vec::new(),
+    };
+    for combination in type_combinations() {
+      let multiplier = self.type_effectiveness.defend(&types_of(&combination))[type_];
+      if is_super_effective(multiplier) {
+        coverage.super_effective.push(combination);
+      } else if is_resisted(multiplier) {
+        coverage.resisted.push(combination);
+      } else {
+        coverage.neutral.push(combination);
+      }
+    }
+    coverage
+  }
+
+  pub fn weaknesses(&self, pokemon: &Pokemon) -> Weaknesses {
+    let mut weaknesses = Weaknesses {
+      weak_to: Vec::new(),
+      neutral: Vec::new(),
+      resists: Vec::new(),
+    };
+    for (attacking, multiplier) in pokemon.type_effectiveness.iter() {
+      if is_super_effective(multiplier) {
+        weaknesses.weak_to.push((attacking, multiplier));
+      } else if is_resisted(multiplier) {
+        weaknesses.resists.push((attacking, multiplier));
+      } else {
+        weaknesses.neutral.push(attacking);
+      }
+    }
+    weaknesses
+  }
+
+  // Coverage of the instance's fast move and (distinct) charged moves
+  pub fn coverage(&self, instance: &PokemonInstance) -> Coverage {
+    let mut moves = vec![
+      (&instance.fast_move.uid, instance.fast_move.type_),
+      (&instance.charged_move1.uid, instance.charged_move1.type_),
+    ];
+    if instance.charged_move2.uid != instance.charged_move1.uid {
+      moves.push((&instance.charged_move2.uid, instance.charged_move2.type_));
+    }
+
+    Coverage {
+      pokemon_id: instance.pokemon.id.clone(),
+      moves: moves
+        .into_iter()
+        .map(|(id, type_)| self.move_coverage(id, type_))
+        .collect(),
+      weaknesses: self.weaknesses(&instance.pokemon),
+    }
+  }
+
+  pub fn team_coverage(&self, team: &[PokemonInstance]) -> TeamCoverage {
+    let members: Vec<Coverage> = team.iter().map(|i| self.coverage(i)).collect();
+
+    let uncovered = type_combinations()
+      .into_iter()
+      .filter(|c| !members.iter().any(|m| m.hits_super_effectively(c)))
+      .collect();
+    let unresisted = TYPE_ORDERING
+      .iter()
+      .filter(|t| {
+        !members
+          .iter()
+          .any(|m| m.weaknesses.resists.iter().any(|(r, _)| r == *t))
+      })
+      .copied()
+      .collect();
+
+    let mut shared_weaknesses: Vec<(Type, usize)> = TYPE_ORDERING
+      .iter()
+      .map(|t| {
+        let weak = members
+          .iter()
+          .filter(|m| m.weaknesses.weak_to.iter().any(|(w, _)| w == t))
+          .count();
+        (*t, weak)
+      })
+      .filter(|(_, weak)| *weak > 0)
+      .collect();
+    shared_weaknesses.sort_by_key(|&(_, weak)| std::cmp::Reverse(weak));
+
+    TeamCoverage {
+      members,
+      uncovered,
+      unresisted,
+      shared_weaknesses,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::gamemaster::test_fixture;
+
+  #[test]
+  fn test_coverage() {
+    use Type::*;
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
+    assert_eq!(type_combinations().len(), 18 + 18 * 17 / 2);
+
+    let registeel = mech.pokemon_instance(
+      "REGISTEEL",
+      Level::new(22, true).unwrap(),
+      15, 2, 5,
+      "LOCK_ON_FAST",
+      "FOCUS_BLAST",
+      Some("FLASH_CANNON"),
+    ).unwrap();
+    let coverage = mech.coverage(&registeel);
+    assert_eq!(coverage.moves.len(), 3);
+
+    let focus_blast = &coverage.moves[1];
+    assert_eq!((focus_blast.move_id.as_str(), focus_blast.type_), ("FOCUS_BLAST", Fighting));
+    assert!(focus_blast.super_effective.contains(&(Normal, None)));
+    assert!(focus_blast.super_effective.contains(&(Rock, Some(Steel))));
+    // 1.6 against ice, 0.625 against flying
+    assert!(focus_blast.neutral.contains(&(Flying, Some(Ice))));
+    assert!(focus_blast.resisted.contains(&(Normal, Some(Ghost))));
+    assert_eq!(
+      focus_blast.super_effective.len() + focus_blast.neutral.len() + focus_blast.resisted.len(),
+      type_combinations().len()
+    );
+    assert!(coverage.hits_super_effectively(&(Fairy, None)));
+    assert!(!coverage.hits_super_effectively(&(Water, None)));
+
+    let weak_to: Vec<Type> = coverage.weaknesses.weak_to.iter().map(|(t, _)| *t).collect();
+    assert_eq!(weak_to, vec![Fighting, Ground, Fire]);
+    assert_eq!(coverage.weaknesses.resists.len(), 11);
+    assert!(coverage.weaknesses.resists.contains(&(Poison, 0.390625)));
+    assert_eq!(coverage.weaknesses.neutral, vec![Ghost, Water, Electric, Dark]);
+
+    // Repeated charged moves count once
+    let single = mech.pokemon_instance(
+      "REGISTEEL",
+      Level::new(22, true).unwrap(),
+      15, 2, 5,
+      "LOCK_ON_FAST",
+      "FOCUS_BLAST",
+      None,
+    ).unwrap();
+    assert_eq!(mech.coverage(&single).moves.len(), 2);
+  }
+
+  #[test]
+  fn test_team_coverage() {
+    use Type::*;
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
+    let registeel = mech.pokemon_instance(
+      "REGISTEEL",
+      Level::new(22, true).unwrap(),
+      15, 2, 5,
+      "LOCK_ON_FAST",
+      "FOCUS_BLAST",
+      Some("FLASH_CANNON"),
+    ).unwrap();
+    let machamp = mech.pokemon_instance(
+      "MACHAMP",
+      Level::new(22, true).unwrap(),
+      15, 2, 5,
+      "COUNTER_FAST",
+      "DYNAMIC_PUNCH",
+      None,
+    ).unwrap();
+
+    let team = mech.team_coverage(&[registeel, machamp]);
+    assert_eq!(team.members.len(), 2);
+    // Only fighting and steel moves, and nothing resists or hits ghosts
+    assert!(team.uncovered.contains(&(Water, None)));
+    assert!(team.uncovered.contains(&(Ghost, None)));
+    assert!(!team.uncovered.contains(&(Ice, None)));
+    assert!(team.unresisted.contains(&Ghost));
+    assert!(!team.unresisted.contains(&Rock));
+    assert_eq!(team.shared_weaknesses[0], (Fighting, 1));
+    assert!(team.shared_weaknesses.contains(&(Psychic, 1)));
+
+    let empty = mech.team_coverage(&[]);
+    assert_eq!(empty.uncovered.len(), type_combinations().len());
+    assert_eq!(empty.unresisted, TYPE_ORDERING.to_vec());
+  }
+}
@@ -0,0 +1,278 @@
+use crate::error::*;
+use crate::model::league::*;
+use crate::model::mechanics::Mechanics;
+use crate::model::pokemon::*;
+use crate::ranker::{rank_table, Encounter, RankEntry};
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+
+// ===================
+// === Observation ===
+// ===================
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Stat {
+  Attack,
+  Defense,
+  Stamina,
+}
+
+// What can be read off a Pokémon in game: its CP and HP, and optionally the
+// appraisal and the CP it would have after one more power-up
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Observation {
+  pub cp: u32,
+  pub hp: u32,
+  // 0 to 4, where 4 is a perfect 15/15/15
+  pub stars: Option<u8>,
+  // Every stat tied for the highest IV
+  pub best_stats: Option<Vec<Stat>>,
+  pub cp_after_power_up: Option<u32>,
+}
+
+impl Observation {
+  pub fn new(cp: u32, hp: u32) -> Observation {
+    Observation { cp, hp, ..Default::default() }
+  }
+
+  pub fn with_stars(self, stars: u8) -> Observation {
+    Observation { stars: Some(stars), ..self }
+  }
+
+  pub fn with_best_stats(self, best_stats: &[Stat]) -> Observation {
+    Observation { best_stats: Some(best_stats.to_vec()), ..self }
+  }
+
+  pub fn with_power_up(self, cp: u32) -> Observation {
+    Observation { cp_after_power_up: Some(cp), ..self }
+  }
+
+  fn matches_appraisal(&self, (atk, def, sta): (Iv, Iv, Iv)) -> bool {
+    let total = atk.value() + def.value() + sta.value();
+    let stars_match = self.stars.is_none_or(|stars| stars == appraisal_stars(total));
+
+    let best = atk.max(def).max(sta);
+    let best_match = self.best_stats.as_ref().is_none_or(|stats| {
+      [(Stat::Attack, atk), (Stat::Defense, def), (Stat::Stamina, sta)]
+        .iter()
+        .all(|(stat, iv)| stats.contains(stat) == (*iv == best))
+    });
+
+    stars_match && best_match
+  }
+}
+
+fn appraisal_stars(total: u16) -> u8 {
+  match total {
+    45 => 4,
+    37..=44 => 3,
+    30..=36 => 2,
+    23..=29 => 1,
+    _ => 0,
+  }
+}
+
+// =================
+// === Candidate ===
+// =================
+
+// One (IVs, level) combination consistent with an observation. `ranks` follows
+// the order of the leagues passed to `Mechanics::infer_ivs`, and is `None` where
+// the Pokémon can't enter the league at or above its current level.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+  pub atk_iv: Iv,
+  pub def_iv: Iv,
+  pub sta_iv: Iv,
+  pub level: Level,
+  pub ranks: Vec<Option<RankEntry>>,
+}
+
+impl Mechanics {
+  // Every combination of IVs and level that shows the observed CP and HP and
+  // fits the appraisal and power-up hints, lowest level first
+  pub fn infer_ivs<S: Into<SpeciesId>>(
+    &self,
+    species: S,
+    observation: &Observation,
+    leagues: &[League],
+  ) -> Result<Vec<Candidate>, Error> {
+    let species = species.into();
+    let pok = self
+      .pokemon(&species)
+      .ok_or_else(|| Error::BoundsError(format!("Could not find pokemon {}", species)))?;
+    if observation.stars.is_some_and(|stars| stars > 4) {
+      return Err(Error::BoundsError(format!(
+        "Appraisals go up to 4 stars, got {:?}",
+        observation.stars
+      )));
+    }
+
+    // Moves don't affect CP or HP, any will do
+    let moves = pok.fast_moves.values().next().zip(pok.charged_moves.values().next());
+    let (fast_move, charged_move) = match moves {
+      Some((f, c)) => (f.clone(), c.clone()),
+      None => return Err(Error::ParseError(format!("{} has no moves", pok.id))),
+    };
+    let instance = |level: Level, cpm: f64, atk, def, sta| {
+      PokemonInstance::new(
+        pok.clone(), level, cpm,
+        atk, def, sta,
+        fast_move.clone(),
+        charged_move.clone(),
+        charged_move.clone(),
+      )
+    };
+
+    let mut candidates = Vec::new();
+    for (level, cpm) in self.levels() {
+      // HP only depends on stamina
+      for sta in Iv::all() {
+        if instance(level, cpm, Iv::MIN, Iv::MIN, sta).hp() != observation.hp {
+          continue;
+        }
+        for atk in Iv::all() {
+          for def in Iv::all() {
+            if !observation.matches_appraisal((atk, def, sta)) {
+              continue;
+            }
+            if instance(level, cpm, atk, def, sta).cp() != observation.cp {
+              continue;
+            }
+            let powered_up = level.next();
+            let power_up_match = observation.cp_after_power_up.is_none_or(|cp| {
+              powered_up > level
+                && powered_up <= self.level_cap
+                && self
+                  .cp_multiplier(&powered_up)
+                  .is_ok_and(|cpm| instance(powered_up, cpm, atk, def, sta).cp() == cp)
+            });
+            if power_up_match {
+              candidates.push(Candidate {
+                atk_iv: atk,
+                def_iv: def,
+                sta_iv: sta,
+                level,
+                ranks: Vec::new(),
+              });
+            }
+          }
+        }
+      }
+    }
+
+    // Rank tables only depend on the league and the candidate's level, as
+    // Pokémon can't be powered down
+    let mut tables: HashMap<(usize, Level), Vec<RankEntry>> = HashMap::new();
+    for c in &mut candidates {
+      let cp = instance(c.level, self.cp_multiplier(&c.level)?, c.atk_iv, c.def_iv, c.sta_iv).cp();
+      let mut ranks = Vec::new();
+      for (i, league) in leagues.iter().enumerate() {
+        let fits = league.allows(&pok)
+          && league.cp_cap.is_none_or(|cap| cp <= cap)
+          && league.level_cap.is_none_or(|cap| c.level <= cap);
+        if !fits {
+          ranks.push(None);
+          continue;
+        }
+        let table = match tables.entry((i, c.level)) {
+          Entry::Occupied(e) => e.into_mut(),
+          Entry::Vacant(e) => {
+            let encounter = Encounter::new(Iv::MIN, c.level);
+            e.insert(rank_table(self, pok.species(), league, &encounter)?)
+          },
+        };
+        let ivs = (c.atk_iv, c.def_iv, c.sta_iv);
+        ranks.push(table.iter().find(|e| (e.atk, e.def, e.sta) == ivs).copied());
+      }
+      c.ranks = ranks;
+    }
+
+    Ok(candidates)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::gamemaster::test_fixture;
+
+  #[test]
+  fn test_infer_ivs() {
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
+    let altaria = |level| mech.pokemon_instance(
+      "ALTARIA", level,
+      13, 7, 11,
+      "DRAGON_BREATH_FAST",
+      "SKY_ATTACK",
+      None,
+    ).unwrap();
+    let l20 = Level::new(20, false).unwrap();
+    let (cp, hp) = (altaria(l20).cp(), altaria(l20).hp());
+    assert_eq!((cp, hp), (1098, 114));
+
+    let mut great = League::new("great", Some(1500));
+    let leagues = [great.clone(), League::new("master", None)];
+    let ivs = |c: &Candidate| (c.atk_iv.value(), c.def_iv.value(), c.sta_iv.value(), c.level);
+
+    let candidates = mech.infer_ivs("ALTARIA", &Observation::new(cp, hp), &leagues).unwrap();
+    assert_eq!(candidates.len(), 18);
+    assert!(candidates.iter().any(|c| ivs(c) == (13, 7, 11, l20)));
+    assert!(candidates.iter().all(|c| c.ranks.len() == 2 && c.ranks.iter().all(Option::is_some)));
+
+    // 2 stars, attack is the best stat
+    let appraised = Observation::new(cp, hp)
+      .with_stars(2)
+      .with_best_stats(&[Stat::Attack]);
+    let candidates = mech.infer_ivs("ALTARIA", &appraised, &leagues).unwrap();
+    assert!(candidates.len() < 18);
+    assert!(candidates.iter().all(|c| {
+      let total = c.atk_iv.value() + c.def_iv.value() + c.sta_iv.value();
+      (30..=36).contains(&total) && c.atk_iv > c.def_iv.max(c.sta_iv)
+    }));
+
+    let powered_up = appraised.with_power_up(altaria(l20.next()).cp());
+    let candidates = mech.infer_ivs("ALTARIA", &powered_up, &leagues).unwrap();
+    assert_eq!(
+      candidates.iter().map(ivs).collect::<Vec<_>>(),
+      vec![(13, 8, 10, l20), (13, 7, 11, l20)]
+    );
+    let rank = candidates[1].ranks[0].unwrap();
+    assert_eq!((rank.atk.value(), rank.def.value(), rank.sta.value(), rank.rank), (13, 7, 11, 3274));
+
+    great.banned.insert(SpeciesId::from("ALTARIA"));
+    let candidates = mech.infer_ivs("ALTARIA", &powered_up, &[great]).unwrap();
+    assert_eq!(candidates[0].ranks, vec![None]);
+
+    // Too strong for great league already
+    let l40 = Level::new(40, false).unwrap();
+    let strong = Observation::new(altaria(l40).cp(), altaria(l40).hp());
+    let candidates = mech.infer_ivs("ALTARIA", &strong, &leagues[..]).unwrap();
+    assert!(!candidates.is_empty());
+    assert!(candidates.iter().all(|c| c.ranks[0].is_none() && c.ranks[1].is_some()));
+
+    // Forms are ranked as themselves
+    let marowak = mech.pokemon_instance(
+      ("MAROWAK", "ALOLA"), l20,
+      13, 7, 11,
+      "HEX_FAST",
+      "SHADOW_BONE",
+      None,
+    ).unwrap();
+    let observation = Observation::new(marowak.cp(), marowak.hp()).with_power_up(
+      mech.pokemon_instance(("MAROWAK", "ALOLA"), l20.next(), 13, 7, 11, "HEX_FAST", "SHADOW_BONE", None).unwrap().cp(),
+    );
+    let candidates = mech.infer_ivs(("MAROWAK", "ALOLA"), &observation, &leagues[..1]).unwrap();
+    assert!(candidates.iter().any(|c| (c.atk_iv.value(), c.def_iv.value(), c.sta_iv.value()) == (13, 7, 11)));
+    let table = rank_table(&mech, ("MAROWAK", "ALOLA"), &leagues[0], &Encounter::new(Iv::MIN, l20)).unwrap();
+    for c in &candidates {
+      let expected = table.iter().find(|e| (e.atk, e.def, e.sta) == (c.atk_iv, c.def_iv, c.sta_iv)).copied();
+      assert_eq!(c.ranks, vec![expected]);
+    }
+
+    assert!(mech.infer_ivs("ALTARIA", &Observation::new(cp, hp).with_stars(5), &[]).is_err());
+    assert!(mech.infer_ivs("ALTARIA", &Observation::new(cp, hp + 1000), &[]).unwrap().is_empty());
+    assert!(mech.infer_ivs("MISSINGNO", &Observation::new(cp, hp), &[]).is_err());
+  }
+}
@@ -0,0 +1,356 @@
+use crate::error::*;
+use crate::gamemaster as gm;
+use crate::model::Type;
+use crate::model::pokemon::*;
+
+use serde::Deserialize;
+
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+// ==============
+// === League ===
+// ==============
+
+// Restrictions a team has to satisfy to enter a league or cup. Empty `types`
+// means any type is allowed; `allowed: None` means any species is allowed.
+// Species in `allowed` and `banned` without a form cover all of their forms.
+#[derive(Debug, Clone)]
+pub struct League {
+  pub id: String,
+  pub cp_cap: Option<u32>,
+  pub level_cap: Option<Level>,
+  pub types: HashSet<Type>,
+  pub allowed: Option<HashSet<SpeciesId>>,
+  pub banned: HashSet<SpeciesId>,
+  // Pokémon per team
+  pub team_size: Option<usize>,
+}
+
+// Whether a list entry names the species. Forms may be given in full
+// ("MAROWAK_ALOLA") or as a suffix ("ALOLA"), and the normal form is also the
+// species' form-less entry.
+fn covers(entry: &SpeciesId, species: &SpeciesId) -> bool {
+  let form_matches = match (&entry.form, &species.form) {
+    (None, _) => true,
+    (Some(entry_form), Some(form)) => {
+      entry_form == form || *form == format!("{}_{}", entry.pokemon, entry_form)
+    }
+    (Some(entry_form), None) => {
+      entry_form == "NORMAL" || *entry_form == format!("{}_NORMAL", entry.pokemon)
+    }
+  };
+  entry.pokemon == species.pokemon && form_matches
+}
+
+impl League {
+  pub fn new(id: &str, cp_cap: Option<u32>) -> League {
+    League {
+      id: id.to_owned(),
+      cp_cap,
+      level_cap: None,
+      types: HashSet::new(),
+      allowed: None,
+      banned: HashSet::new(),
+      team_size: None,
+    }
+  }
+
+  // Species-level rules only: CP and level depend on the instance
+  pub fn allows(&self, pokemon: &Pokemon) -> bool {
+    let type_allowed = self.types.is_empty()
+      || self.types.contains(&pokemon.type1)
+      || pokemon.type2.is_some_and(|t| self.types.contains(&t));
+    let species = pokemon.species();
+    let species_allowed = self
+      .allowed
+      .as_ref()
+      .is_none_or(|a| a.iter().any(|entry| covers(entry, &species)));
+    let banned = self.banned.iter().any(|entry| covers(entry, &species));
+
+    type_allowed && species_allowed && !banned
+  }
+
+  pub fn check(&self, instance: &PokemonInstance) -> Result<(), Error> {
+    if !self.allows(&instance.pokemon) {
+      return Err(Error::BoundsError(format!(
+        "{} is not allowed in {}",
+        instance.pokemon.id, self.id
+      )));
+    }
+    if let Some(cap) = self.cp_cap {
+      if instance.cp() > cap {
+        return Err(Error::BoundsError(format!(
+          "{} CP {} exceeds the {} cap of {}",
+          instance.pokemon.id,
+          instance.cp(),
+          self.id,
+          cap
+        )));
+      }
+    }
+    if let Some(cap) = self.level_cap {
+      if instance.level() > cap {
+        return Err(Error::BoundsError(format!(
+          "{} level {:?} exceeds the {} cap of {:?}",
+          instance.pokemon.id,
+          instance.level(),
+          self.id,
+          cap
+        )));
+      }
+    }
+    Ok(())
+  }
+
+  // `check` for every member, and the team size
+  pub fn check_team(&self, team: &[PokemonInstance]) -> Result<(), Error> {
+    if let Some(size) = self.team_size {
+      if team.len() > size {
+        return Err(Error::BoundsError(format!(
+          "{} allows teams of {}, got {}",
+          self.id,
+          size,
+          team.len()
+        )));
+      }
+    }
+    team.iter().try_for_each(|p| self.check(p))
+  }
+
+  /// Loads user-defined cups from a JSON array of `CupDefinition`s.
+  pub fn load_cups<P: AsRef<Path>>(path: P) -> Result<Vec<League>, Error> {
+    let path = path.as_ref();
+    let file = File::open(path).map_err(|e| {
+      Error::IoError(format!("Couldn't open cups {}: {}", path.display(), e))
+    })?;
+    League::cups_from_reader(BufReader::new(file))
+  }
+
+  pub fn cups_from_reader<R: Read>(reader: R) -> Result<Vec<League>, Error> {
+    serde_json::from_reader::<_, Vec<CupDefinition>>(reader)
+      .map_err(|e| Error::ParseError(format!("Couldn't parse cups: {}", e)))?
+      .iter()
+      .map(League::try_from)
+      .collect()
+  }
+}
+
+impl TryFrom<(&str, &gm::CombatLeague)> for League {
+  type Error = Error;
+
+  fn try_from((id, l): (&str, &gm::CombatLeague)) -> Result<Self, Self::Error> {
+    if !l.enabled {
+      return Err(Error::BoundsError(format!("League {} is disabled", id)));
+    }
+    let mut league = League::new(id, None);
+    league.team_size = l.pokemon_count.map(|c| c as usize);
+    league.banned.extend(l.banned_pokemon.iter().map(|p| SpeciesId::new(p, None)));
+    let species = |p: &gm::PokemonListEntry| SpeciesId::new(&p.id, p.form.as_deref());
+
+    for c in &l.pokemon_condition {
+      if let Some(cp) = &c.with_pokemon_cp_limit {
+        league.cp_cap = cp.max_cp;
+      }
+      if let Some(t) = &c.with_pokemon_type {
+        for t in &t.pokemon_type {
+          league.types.insert(Type::try_from(t.as_str())?);
+        }
+      }
+      if let Some(w) = &c.pokemon_white_list {
+        league
+          .allowed
+          .get_or_insert_with(HashSet::new)
+          .extend(w.pokemon.iter().map(species));
+      }
+      if let Some(b) = &c.pokemon_ban_list {
+        league.banned.extend(b.pokemon.iter().map(species));
+      }
+      if let Some(r) = &c.pokemon_level_range {
+        league.level_cap = r.max_level.map(|level| Level::new(level, false)).transpose()?;
+      }
+    }
+
+    Ok(league)
+  }
+}
+
+// ===========
+// === Cup ===
+// ===========
+
+// Format of user-defined cups, e.g.
+// { "id": "kanto", "cpCap": 1500, "types": ["POKEMON_TYPE_FIRE"], "banned": ["CHARIZARD"] }
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CupDefinition {
+  pub id: String,
+  pub cp_cap: Option<u32>,
+  pub level_cap: Option<u16>,
+  pub team_size: Option<usize>,
+  #[serde(default)]
+  pub types: Vec<String>,
+  pub allowed: Option<Vec<CupPokemon>>,
+  #[serde(default)]
+  pub banned: Vec<CupPokemon>,
+}
+
+// Either every form of a species, "MAROWAK", or one of them,
+// { "id": "MAROWAK", "form": "MAROWAK_ALOLA" }
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum CupPokemon {
+  Species(String),
+  Form { id: String, form: String },
+}
+
+impl From<&CupPokemon> for SpeciesId {
+  fn from(p: &CupPokemon) -> SpeciesId {
+    match p {
+      CupPokemon::Species(id) => SpeciesId::new(id, None),
+      CupPokemon::Form { id, form } => SpeciesId::new(id, Some(form)),
+    }
+  }
+}
+
+impl TryFrom<&CupDefinition> for League {
+  type Error = Error;
+
+  fn try_from(c: &CupDefinition) -> Result<Self, Self::Error> {
+    Ok(League {
+      id: c.id.clone(),
+      cp_cap: c.cp_cap,
+      level_cap: c.level_cap.map(|level| Level::new(level, false)).transpose()?,
+      types: c
+        .types
+        .iter()
+        .map(|t| Type::try_from(t.as_str()))
+        .collect::<Result<_, _>>()?,
+      allowed: c.allowed.as_ref().map(|a| a.iter().map(SpeciesId::from).collect()),
+      banned: c.banned.iter().map(SpeciesId::from).collect(),
+      team_size: c.team_size,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::gamemaster::{test_fixture, GameMaster};
+  use crate::model::Mechanics;
+
+  #[test]
+  fn test_combat_league() {
+    let gm = GameMaster::from_reader(
+      r#"{"itemTemplates": [{
+        "templateId": "COMBAT_LEAGUE_VS_SEEKER_FLYING_GREAT",
+        "combatLeague": {
+          "title": "combat_flying_cup",
+          "enabled": true,
+          "pokemonCondition": [
+            { "type": "WITH_POKEMON_CP_LIMIT", "withPokemonCpLimit": { "maxCp": 1500 } },
+            { "type": "WITH_POKEMON_TYPE", "withPokemonType": { "pokemonType": ["POKEMON_TYPE_FLYING"] } },
+            { "type": "POKEMON_BANLIST", "pokemonBanList": { "pokemon": [{ "id": "NOCTOWL" }] } },
+            { "type": "WITH_POKEMON_TYPE", "withPokemonType": { "pokemonType": ["POKEMON_TYPE_GROUND"] } },
+            { "type": "POKEMON_BANLIST", "pokemonBanList": { "pokemon": [{ "id": "MAROWAK", "form": "MAROWAK_ALOLA" }] } }
+          ],
+          "pokemonCount": 3,
+          "bannedPokemon": ["CHARIZARD"]
+        }
+      }]}"#
+        .as_bytes(),
+    )
+    .unwrap();
+
+    let league = match &gm.item_templates[0].entry {
+      Some(gm::GameMasterEntry::CombatLeague(l)) => {
+        League::try_from((gm.item_templates[0].template_id.as_str(), l)).unwrap()
+      }
+      e => panic!("Expected a CombatLeague, got {:?}", e),
+    };
+
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
+    assert_eq!(league.cp_cap, Some(1500));
+    assert!(league.allows(&mech.pokemon("ALTARIA").unwrap()));
+    assert!(league.allows(&mech.pokemon("SKARMORY").unwrap()));
+    assert!(!league.allows(&mech.pokemon("NOCTOWL").unwrap()));
+    assert!(!league.allows(&mech.pokemon("CHARIZARD").unwrap()));
+    assert!(!league.allows(&mech.pokemon("REGISTEEL").unwrap()));
+    // Only the Alolan form is banned
+    assert!(league.allows(&mech.pokemon("MAROWAK").unwrap()));
+    assert!(league.allows(&mech.pokemon(("MAROWAK", "MAROWAK_NORMAL")).unwrap()));
+    assert!(!league.allows(&mech.pokemon(("MAROWAK", "ALOLA")).unwrap()));
+    assert_eq!(league.team_size, Some(3));
+
+    let disabled = GameMaster::from_reader(
+      r#"{"itemTemplates": [{
+        "templateId": "COMBAT_LEAGUE_RETIRED",
+        "combatLeague": { "title": "combat_retired_cup", "enabled": false }
+      }]}"#
+        .as_bytes(),
+    )
+    .unwrap();
+    match &disabled.item_templates[0].entry {
+      Some(gm::GameMasterEntry::CombatLeague(l)) => assert!(League::try_from(("COMBAT_LEAGUE_RETIRED", l)).is_err()),
+      e => panic!("Expected a CombatLeague, got {:?}", e),
+    }
+
+    let great = mech.league("COMBAT_LEAGUE_DEFAULT_GREAT").unwrap();
+    assert_eq!(great.cp_cap, Some(1500));
+    assert!(great.allows(&mech.pokemon("REGISTEEL").unwrap()));
+    assert_eq!(mech.league("COMBAT_LEAGUE_DEFAULT_MASTER").unwrap().cp_cap, None);
+  }
+
+  #[test]
+  fn test_cups() {
+    let cups = League::cups_from_reader(
+      r#"[
+        { "id": "steel", "cpCap": 1500, "levelCap": 40, "types": ["POKEMON_TYPE_STEEL"] },
+        { "id": "lucario", "allowed": ["LUCARIO"] },
+        { "id": "bones", "teamSize": 1, "banned": [{ "id": "MAROWAK", "form": "ALOLA" }] },
+        { "id": "alola", "allowed": [{ "id": "MAROWAK", "form": "MAROWAK_ALOLA" }], "banned": ["STUNFISK"] }
+      ]"#
+        .as_bytes(),
+    )
+    .unwrap();
+
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
+    let registeel = mech.pokemon_instance(
+      "REGISTEEL",
+      Level::new(22, true).unwrap(),
+      15, 2, 5,
+      "LOCK_ON_FAST",
+      "FOCUS_BLAST",
+      Some("FLASH_CANNON"),
+    ).unwrap();
+    let maxed_registeel = mech.pokemon_instance(
+      "REGISTEEL",
+      Level::new(40, false).unwrap(),
+      15, 15, 15,
+      "LOCK_ON_FAST",
+      "FOCUS_BLAST",
+      Some("FLASH_CANNON"),
+    ).unwrap();
+
+    assert!(cups[0].check(&registeel).is_ok());
+    assert!(cups[0].check(&maxed_registeel).is_err());
+    assert!(cups[1].check(&maxed_registeel).is_err());
+    assert!(cups[1].allows(&mech.pokemon("LUCARIO").unwrap()));
+
+    let marowak = mech.pokemon("MAROWAK").unwrap();
+    let alolan = mech.pokemon(("MAROWAK", "ALOLA")).unwrap();
+    assert!(cups[2].allows(&marowak) && !cups[2].allows(&alolan));
+    assert!(!cups[3].allows(&marowak) && cups[3].allows(&alolan));
+    // Banning a species bans all of its forms
+    assert!(!cups[3].allows(&mech.pokemon(("STUNFISK", "GALARIAN")).unwrap()));
+
+    assert!(cups[2].check_team(std::slice::from_ref(&registeel)).is_ok());
+    assert!(cups[2].check_team(&[registeel.clone(), registeel.clone()]).is_err());
+    assert!(cups[0].check_team(&[registeel.clone(), maxed_registeel.clone()]).is_err());
+
+    assert!(League::cups_from_reader(r#"[{ "id": "x", "types": ["FIRE"] }]"#.as_bytes()).is_err());
+  }
+}
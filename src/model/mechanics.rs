@@ -1,11 +1,15 @@
 use crate::error::*;
 use crate::gamemaster::{self as gm, GameMaster};
-use crate::model::{Type, TYPE_ORDERING};
+use crate::model::Type;
+use crate::model::league::*;
 use crate::model::moves::*;
 use crate::model::pokemon::*;
+use crate::model::settings::*;
+use crate::model::type_chart::*;
 
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::sync::Arc;
 
 // =================
 // === Mechanics ===
@@ -14,55 +18,59 @@ use std::convert::TryFrom;
 pub struct Mechanics {
   // gamemaster: &'a gm::GameMaster,
 
-  pokemon: Vec<Pokemon>,
-  pub fast_moves: HashMap<String, FastMove>,
-  pub charged_moves: HashMap<String, ChargedMove>,
-
-  pub cp_multiplier: [f64; 79],
-  pub type_effectiveness: HashMap<Type, HashMap<Type, f64>>,
+  // Every form of a species, in GameMaster order
+  pokemon: HashMap<String, Vec<Arc<Pokemon>>>,
+  forms: HashMap<String, Vec<String>>,
+  families: HashMap<String, Vec<SpeciesId>>,
+  pub fast_moves: HashMap<String, Arc<FastMove>>,
+  pub charged_moves: HashMap<String, Arc<ChargedMove>>,
+
+  // One entry per half level, from level 1 to the last level in the GameMaster
+  pub cp_multiplier: Vec<f64>,
+  // Highest level Pokémon instances and the ranker may reach
+  pub level_cap: Level,
+  pub type_effectiveness: TypeChart,
+  pub leagues: HashMap<String, League>,
+  pub upgrades: UpgradeSettings,
+  pub combat_settings: CombatSettings,
+  pub stat_stages: StatStageSettings,
 }
 
 lazy_static! {
-  static ref MECHANICS: Mechanics = Mechanics::new().unwrap();
+  static ref MECHANICS: Mechanics = Mechanics::from_gamemaster(GameMaster::instance())
+    .unwrap_or_else(|e| panic!("Couldn't build the default Mechanics: {}", e));
 }
 
 impl Mechanics {
+  /// Process-wide Mechanics built from `GameMaster::instance()`. Panics if the
+  /// default GameMaster can't be loaded; use `from_gamemaster` to handle errors.
   pub fn instance() -> &'static Mechanics {
     &MECHANICS
   }
 
-  fn new() -> Result<Mechanics, Error> {
-    let gm = GameMaster::instance();
+  pub fn from_gamemaster(gm: &GameMaster) -> Result<Mechanics, Error> {
     let fast_moves = {
       gm.item_templates
         .iter()
         .filter_map(|i| match &i.entry {
-          Some(gm::GameMasterEntry::PvPMove(m)) => {
-            if m.energy_delta >= 0 {
-              Some((m.unique_id.to_owned(), FastMove::try_from(m).unwrap()))
-            } else {
-              None
-            }
+          Some(gm::GameMasterEntry::PvPMove(m)) if m.energy_delta >= 0 => {
+            Some(FastMove::try_from(m).map(|fm| (m.unique_id.to_owned(), Arc::new(fm))))
           }
           _ => None,
         })
-        .collect()
+        .collect::<Result<HashMap<_, _>, _>>()?
     };
 
     let charged_moves = {
       gm.item_templates
         .iter()
         .filter_map(|i| match &i.entry {
-          Some(gm::GameMasterEntry::PvPMove(m)) => {
-            if m.energy_delta < 0 {
-              Some((m.unique_id.to_owned(), ChargedMove::try_from(m).unwrap()))
-            } else {
-              None
-            }
+          Some(gm::GameMasterEntry::PvPMove(m)) if m.energy_delta < 0 => {
+            Some(ChargedMove::try_from(m).map(|cm| (m.unique_id.to_owned(), Arc::new(cm))))
           }
           _ => None,
         })
-        .collect()
+        .collect::<Result<HashMap<_, _>, _>>()?
     };
 
     let type_effectiveness = {
@@ -75,31 +83,94 @@ impl Mechanics {
         })
         .collect::<Vec<_>>();
 
-      types
-        .iter()
-        .map(|t| {
-          (
-            Type::try_from(t.attack_type.as_str()).unwrap(),
-            t.effectiveness
-              .iter()
-              .take(TYPE_ORDERING.len())
-              .enumerate()
-              .map(|(idx, v)| (TYPE_ORDERING[idx], *v))
-              .collect(),
-          )
-        })
-        .collect()
+      TypeChart::try_from(types.as_slice())?
     };
 
-    Ok(Mechanics {
-      pokemon: Mechanics::build_pokemons(&gm, &fast_moves, &charged_moves, &type_effectiveness)?,
+    let leagues = gm
+      .item_templates
+      .iter()
+      .filter_map(|i| match &i.entry {
+        // Disabled leagues can't be entered
+        Some(gm::GameMasterEntry::CombatLeague(l)) if l.enabled => {
+          Some(League::try_from((i.template_id.as_str(), l)).map(|l| (i.template_id.clone(), l)))
+        }
+        _ => None,
+      })
+      .collect::<Result<HashMap<_, _>, _>>()?;
+
+    let upgrades = gm
+      .item_templates
+      .iter()
+      .find_map(|i| match &i.entry {
+        Some(gm::GameMasterEntry::PokemonUpgrades(u)) => Some(UpgradeSettings::from(u)),
+        _ => None,
+      })
+      .ok_or_else(|| {
+        Error::ParseError("Couldn't find PokemonUpgrades in GameMaster".to_owned())
+      })?;
+
+    let combat_settings = gm
+      .item_templates
+      .iter()
+      .find_map(|i| match &i.entry {
+        Some(gm::GameMasterEntry::CombatSettings(s)) => Some(CombatSettings::try_from(s)),
+        _ => None,
+      })
+      .unwrap_or_else(|| {
+        Err(Error::ParseError(
+          "Couldn't find CombatSettings in GameMaster".to_owned(),
+        ))
+      })?;
+
+    let stat_stages = gm
+      .item_templates
+      .iter()
+      .find_map(|i| match &i.entry {
+        Some(gm::GameMasterEntry::CombatStatStageSettings(s)) => Some(StatStageSettings::try_from(s)),
+        _ => None,
+      })
+      .unwrap_or_else(|| {
+        Err(Error::ParseError(
+          "Couldn't find CombatStatStageSettings in GameMaster".to_owned(),
+        ))
+      })?;
+
+    let forms = gm
+      .item_templates
+      .iter()
+      .filter_map(|i| match &i.entry {
+        Some(gm::GameMasterEntry::Form(f)) => Some((
+          f.pokemon.clone(),
+          f.forms.iter().map(|d| d.form.clone()).collect(),
+        )),
+        _ => None,
+      })
+      .collect();
+
+    let mut families: HashMap<String, Vec<SpeciesId>> = HashMap::new();
+    for i in &gm.item_templates {
+      if let Some(gm::GameMasterEntry::PokemonSettings(ps)) = &i.entry {
+        families
+          .entry(ps.family_id.clone())
+          .or_default()
+          .push(SpeciesId::new(&ps.pokemon_id, ps.form.as_deref()));
+      }
+    }
+
+    let mechanics = Mechanics {
+      pokemon: Mechanics::build_pokemons(gm, &fast_moves, &charged_moves, &type_effectiveness)?,
+      forms,
+      families,
       fast_moves,
       charged_moves,
       type_effectiveness,
+      leagues,
+      upgrades,
+      combat_settings,
+      stat_stages,
       cp_multiplier: {
-        let pl = gm.item_templates.iter().find(|i| match &i.entry {
-          Some(gm::GameMasterEntry::PlayerLevel(_)) => true,
-          _ => false,
+        let pl = gm.item_templates.iter().find(|i| {
+          matches!(&i.entry, Some(gm::GameMasterEntry::PlayerLevel(_)))
         });
 
         let cpm = match pl {
@@ -119,36 +190,127 @@ impl Mechanics {
           ))),*/
         }?;
 
-        if cpm.len() != 45 {
-          return Err(Error::ParseError(format!(
-            "{} != 45 CPM entries",
-            cpm.len()
-          )));
+        if cpm.is_empty() {
+          return Err(Error::ParseError("PlayerLevel has no CPM entries".to_owned()));
         }
 
-        let mut v = [0f64; 79];
-        for i in 0..39 {
-          v[i * 2] = cpm[i];
-          v[i * 2 + 1] = ((cpm[i] * cpm[i] + cpm[i + 1] * cpm[i + 1]) / 2.).sqrt()
+        // Whole levels come from the GameMaster; half levels sit halfway
+        // between their neighbours in CPM²
+        let mut v = Vec::with_capacity(cpm.len() * 2 - 1);
+        for w in cpm.windows(2) {
+          v.push(w[0]);
+          v.push(((w[0] * w[0] + w[1] * w[1]) / 2.).sqrt());
         }
-        v[78] = cpm[39];
+        v.push(cpm[cpm.len() - 1]);
 
         v
       },
-    })
+      level_cap: Level::MAX_BEST_BUDDY,
+    };
+
+    // Older GameMasters stop at level 40 (or list levels nobody can reach)
+    let level_cap = std::cmp::min(mechanics.level_cap, mechanics.max_level());
+    Ok(Mechanics { level_cap, ..mechanics })
+  }
+
+  // Replaces the default cap of best buddy level 51, e.g. to rank builds without
+  // XL candy. The cap can't exceed the CPM table.
+  pub fn with_level_cap(self, level_cap: Level) -> Result<Mechanics, Error> {
+    if level_cap > self.max_level() {
+      return Err(Error::BoundsError(format!(
+        "Level cap {} is above the highest level {} in the GameMaster",
+        level_cap,
+        self.max_level()
+      )));
+    }
+    Ok(Mechanics { level_cap, ..self })
+  }
+
+  // Highest level with a known CPM
+  pub fn max_level(&self) -> Level {
+    Level::try_from((self.cp_multiplier.len() - 1) as u16).unwrap_or(Level::MAX)
   }
 
-  pub fn pokemon(&self, id: &str) -> Option<Pokemon> {
+  // Every level up to `level_cap`, with its CPM
+  pub fn levels(&self) -> impl Iterator<Item = (Level, f64)> + '_ {
+    let cap = u16::from(&self.level_cap) as usize;
     self
-      .pokemon
+      .cp_multiplier
       .iter()
-      .find(|i| i.id == id)
-      .map(Pokemon::clone)
+      .take(cap + 1)
+      .enumerate()
+      .filter_map(|(i, cpm)| Level::try_from(i as u16).ok().map(|l| (l, *cpm)))
   }
 
-  pub fn pokemon_instance(
-    &self, 
-    pokemon_id: &str,
+  // Shared with every instance of the species; cloning the Arc doesn't copy it
+  pub fn pokemon<'a, S: Into<SpeciesKey<'a>>>(&self, id: S) -> Option<Arc<Pokemon>> {
+    self.find_pokemon(id.into()).cloned()
+  }
+
+  // Forms may be given either in full ("MAROWAK_ALOLA") or as a suffix
+  // ("ALOLA"). Without a form, fall back to the first one in formSettings
+  // (usually "<POKEMON>_NORMAL") when the GameMaster has no form-less entry.
+  // Doesn't allocate, as instances are built in bulk.
+  fn find_pokemon(&self, id: SpeciesKey) -> Option<&Arc<Pokemon>> {
+    let forms = self.pokemon.get(id.pokemon)?;
+    let with_form = |form: &str| forms.iter().find(|p| p.form.as_deref() == Some(form));
+    match id.form {
+      Some(form) => with_form(form).or_else(|| {
+        forms.iter().find(|p| {
+          p.form
+            .as_deref()
+            .and_then(|f| f.strip_prefix(id.pokemon))
+            .and_then(|f| f.strip_prefix('_'))
+            == Some(form)
+        })
+      }),
+      None => forms
+        .iter()
+        .find(|p| p.form.is_none())
+        .or_else(|| self.forms(id.pokemon).first().and_then(|form| with_form(form))),
+    }
+  }
+
+  pub fn forms(&self, pokemon: &str) -> &[String] {
+    self.forms.get(pokemon).map_or(&[], Vec::as_slice)
+  }
+
+  // Direct evolutions only; follow them with further calls for the full chain
+  pub fn evolutions_of<'a, S: Into<SpeciesKey<'a>>>(&self, species: S) -> &[Evolution] {
+    self
+      .find_pokemon(species.into())
+      .map_or(&[], |p| p.evolutions.as_slice())
+  }
+
+  pub fn pre_evolutions_of<'a, S: Into<SpeciesKey<'a>>>(&self, species: S) -> Vec<SpeciesId> {
+    let target = match self.find_pokemon(species.into()) {
+      Some(p) => p,
+      None => return vec![],
+    };
+    self
+      .family_of(target.key())
+      .iter()
+      .filter(|s| {
+        self.evolutions_of(*s).iter().any(|e| {
+          self.find_pokemon(e.species.key()).is_some_and(|p| Arc::ptr_eq(p, target))
+        })
+      })
+      .cloned()
+      .collect()
+  }
+
+  // Every species and form sharing the family, in GameMaster order
+  pub fn family_of<'a, S: Into<SpeciesKey<'a>>>(&self, species: S) -> &[SpeciesId] {
+    self
+      .find_pokemon(species.into())
+      .and_then(|p| self.families.get(&p.family))
+      .map_or(&[], Vec::as_slice)
+  }
+
+  #[allow(clippy::too_many_arguments)]
+  pub fn pokemon_instance<'a, S: Into<SpeciesKey<'a>>>(
+    &self,
+    species: S,
     level: Level,
     atk_iv: u16,
     def_iv: u16,
@@ -157,7 +319,44 @@ impl Mechanics {
     charged_move1: &str,
     charged_move2: Option<&str>
   ) -> Result<PokemonInstance, Error> {
-    if let Some(pok) = self.pokemon(pokemon_id) {
+    self.pokemon_variant_instance(
+      species, Variant::Normal, level,
+      atk_iv, def_iv, sta_iv,
+      fast_move, charged_move1, charged_move2,
+    )
+  }
+
+  // Frustration is only available to shadow Pokémon and Return only to
+  // purified ones, on top of the species' regular movepool
+  #[allow(clippy::too_many_arguments)]
+  pub fn pokemon_variant_instance<'a, S: Into<SpeciesKey<'a>>>(
+    &self,
+    species: S,
+    variant: Variant,
+    level: Level,
+    atk_iv: u16,
+    def_iv: u16,
+    sta_iv: u16,
+    fast_move: &str,
+    charged_move1: &str,
+    charged_move2: Option<&str>
+  ) -> Result<PokemonInstance, Error> {
+    let species = species.into();
+    if let Some(pok) = self.pokemon(species) {
+      if variant != Variant::Normal && pok.shadow_move.is_none() {
+        return Err(Error::BoundsError(format!(
+          "{} has no {:?} variant",
+          species, variant
+        )));
+      }
+
+      if level > self.level_cap {
+        return Err(Error::BoundsError(format!(
+          "Level {} is above the level cap {}",
+          level, self.level_cap
+        )));
+      }
+
       let charged_move2 = charged_move2.unwrap_or(charged_move1);
 
       let fast_move = match pok.fast_moves.get(fast_move) {
@@ -170,54 +369,76 @@ impl Mechanics {
         }
       };
 
-      let charged_move1 = match pok.charged_moves.get(charged_move1) {
-        Some(i) => i.clone(),
-        None => {
-          return Err(Error::ParseError(format!(
-            "Charged move {} not found for {}",
-            charged_move1, pok.id
-          )))
-        }
-      };
-
-      let charged_move2 = match pok.charged_moves.get(charged_move2) {
-        Some(i) => i.clone(),
-        None => {
-          return Err(Error::ParseError(format!(
-            "Charged move {} not found for {}",
-            charged_move2, pok.id
-          )))
-        }
-      };
+      let charged_move1 = Mechanics::variant_charged_move(&pok, variant, charged_move1)?;
+      let charged_move2 = Mechanics::variant_charged_move(&pok, variant, charged_move2)?;
 
-      let cpm = self.cp_multiplier(&level);
+      let cpm = self.cp_multiplier(&level)?;
 
       Ok(PokemonInstance::new(
         pok, level, cpm,
-        atk_iv, def_iv, sta_iv,
+        Iv::new(atk_iv)?, Iv::new(def_iv)?, Iv::new(sta_iv)?,
         fast_move,
         charged_move1,
         charged_move2,
-      ))
+      ).with_variant(variant, &self.combat_settings))
     } else {
-      Err(Error::BoundsError(format!("Could not find pokemon {}", pokemon_id)))
+      Err(Error::BoundsError(format!("Could not find pokemon {}", species)))
     }
   }
 
-  pub fn fast_move(&self, id: &str) -> Option<FastMove> {
-    self
-      .fast_moves
-      .iter()
-      .find(|(k, _)| k == &id)
-      .map(|(_, v)| FastMove::clone(v))
+  fn variant_charged_move(pok: &Pokemon, variant: Variant, id: &str) -> Result<Arc<ChargedMove>, Error> {
+    let exclusive = [(&pok.shadow_move, Variant::Shadow), (&pok.purified_move, Variant::Purified)];
+    match exclusive.iter().find(|(m, _)| m.as_ref().is_some_and(|m| m.uid == id)) {
+      Some((Some(m), v)) if *v == variant => Ok(m.clone()),
+      Some((_, v)) => Err(Error::BoundsError(format!(
+        "Charged move {} is only available to {:?} {}",
+        id, v, pok.id
+      ))),
+      None => match pok.charged_moves.get(id) {
+        Some(i) => Ok(i.clone()),
+        None => Err(Error::ParseError(format!(
+          "Charged move {} not found for {}",
+          id, pok.id
+        ))),
+      },
+    }
   }
 
-  pub fn charged_move(&self, id: &str) -> Option<ChargedMove> {
-    self
-      .charged_moves
-      .iter()
-      .find(|(k, _)| k == &id)
-      .map(|(_, v)| ChargedMove::clone(v))
+  // Stardust and (XL) candy needed to take `instance` to `target`, and to
+  // unlock its second charged move if requested. Shadow Pokémon can't be lucky.
+  pub fn power_up_cost(
+    &self,
+    instance: &PokemonInstance,
+    target: Level,
+    lucky: bool,
+    unlock_second_move: bool,
+  ) -> Result<PowerUpCost, Error> {
+    let variant = instance.variant();
+    if lucky && variant == Variant::Shadow {
+      return Err(Error::BoundsError(format!(
+        "Shadow {} can't be lucky",
+        instance.pokemon.id
+      )));
+    }
+
+    let power_up = self.upgrades.power_up_cost(instance.level(), target, variant, lucky)?;
+    if unlock_second_move {
+      Ok(power_up + self.upgrades.unlock_cost(&instance.pokemon.third_move_cost, variant))
+    } else {
+      Ok(power_up)
+    }
+  }
+
+  pub fn league(&self, id: &str) -> Option<&League> {
+    self.leagues.get(id)
+  }
+
+  pub fn fast_move(&self, id: &str) -> Option<Arc<FastMove>> {
+    self.fast_moves.get(id).cloned()
+  }
+
+  pub fn charged_move(&self, id: &str) -> Option<Arc<ChargedMove>> {
+    self.charged_moves.get(id).cloned()
   }
 
   //
@@ -227,11 +448,11 @@ impl Mechanics {
   //
   fn build_pokemons(
     gamemaster: &gm::GameMaster, 
-    fast_moves: &HashMap<String, FastMove>,
-    charged_moves: &HashMap<String, ChargedMove>,
-    type_effectiveness: &HashMap<Type, HashMap<Type, f64>>,
-  ) -> Result<Vec<Pokemon>, Error> {
-    gamemaster
+    fast_moves: &HashMap<String, Arc<FastMove>>,
+    charged_moves: &HashMap<String, Arc<ChargedMove>>,
+    type_effectiveness: &TypeChart,
+  ) -> Result<HashMap<String, Vec<Arc<Pokemon>>>, Error> {
+    let pokemon = gamemaster
       .item_templates
       .iter()
       .filter_map(|i| match &i.entry {
@@ -247,98 +468,97 @@ impl Mechanics {
             },
             _ => None,
           };
-          Some(Ok(Pokemon {
+          let exclusive_move = |id: &str| charged_moves.get(id).cloned();
+          let pokemon = Pokemon {
             id: ps.pokemon_id.clone(),
-            fast_moves: fast_moves
+            form: ps.form.clone(),
+            family: ps.family_id.clone(),
+            evolutions: ps
+              .evolution_branch
               .iter()
-              .filter_map(|(i, v)| {
-                if ps.quick_moves.iter().any(|x| x == i) {
-                  Some((i.clone(), v.clone()))
-                } else {
-                  None
-                }
-              })
+              .filter_map(|b| Evolution::from_branch(b, ps))
               .collect(),
-            charged_moves: charged_moves
+            fast_moves: ps
+              .quick_moves
               .iter()
-              .filter_map(|(i, v)| {
-                if ps.cinematic_moves.iter().any(|x| x == i) {
-                  Some((i.clone(), v.clone()))
-                } else {
-                  None
-                }
-              })
+              .filter_map(|i| fast_moves.get(i).map(|m| (i.clone(), m.clone())))
               .collect(),
-            type1: type1,
-            type2: type2,
+            charged_moves: ps
+              .cinematic_moves
+              .iter()
+              .filter_map(|i| charged_moves.get(i).map(|m| (i.clone(), m.clone())))
+              .collect(),
+            type1,
+            type2,
             stats: ps.stats,
             type_effectiveness: match type2 {
-              Some(t) => Mechanics::defender_dual_type_effectiveness_internal(type_effectiveness, type1, t),
-              None => Mechanics::defender_type_effectiveness_internal(type_effectiveness, type1)
-              //Some(t) => Mechanics::dual_type_effectiveness_internal(type_effectiveness, type1, t),
-              // None => type_effectiveness[&type1].clone(),
+              Some(t) => type_effectiveness.defend(&[type1, t]),
+              None => type_effectiveness.defend(&[type1]),
+            },
+            shadow_move: ps.shadow.as_ref().and_then(|s| exclusive_move(&s.shadow_charge_move)),
+            purified_move: ps.shadow.as_ref().and_then(|s| exclusive_move(&s.purified_charge_move)),
+            third_move_cost: PowerUpCost {
+              stardust: ps.third_move.stardust_to_unlock,
+              candy: ps.third_move.candy_to_unlock,
+              xl_candy: 0,
             },
-          }))
+          };
+          Some(Ok(Arc::new(pokemon)))
         }
         _ => None,
       })
-      .collect()
-  }
+      .collect::<Result<Vec<_>, _>>()?;
 
-  //
-  // TODO
-  // This really warrants numerical constrained types, which aren't a thing yet.
-  // Could use Results but it would add so much more code and it isn't worth it
-  // as the checking should be performed upstream -- no Level into u16 should ever
-  // be above 79 in a Pokémon, and if it is, then there's some inconsistency.
-  // Should perform the check at the input boundary, i.e. JSON deserialization
-  // and whatnot and, until we have more powerful instruments to check for it,
-  // assume it's never going to be above 79.
-  //
-  pub fn cp_multiplier(&self, l: &Level) -> f64 {
-    let l: u16 = l.into();
-    // if l > 79 {
-    //  return Err(Error::BoundsError(format!("Sought CPM for level {} > 40", l)));
-    //}
-    self.cp_multiplier[l as usize]
+    let mut by_id: HashMap<String, Vec<Arc<Pokemon>>> = HashMap::new();
+    for p in pokemon {
+      by_id.entry(p.id.clone()).or_default().push(p);
+    }
+    Ok(by_id)
   }
 
-  pub fn defender_type_effectiveness(&self, a: Type) -> HashMap<Type, f64> {
-    Mechanics::defender_type_effectiveness_internal(&self.type_effectiveness, a)
+  pub fn cp_multiplier(&self, l: &Level) -> Result<f64, Error> {
+    self.cp_multiplier.get(u16::from(l) as usize).copied().ok_or_else(|| {
+      Error::BoundsError(format!(
+        "No CPM for level {}, the GameMaster stops at {}",
+        l,
+        self.max_level()
+      ))
+    })
   }
+}
 
-  fn defender_type_effectiveness_internal(type_effectiveness: &HashMap<Type, HashMap<Type, f64>>, a: Type) -> HashMap<Type, f64> {
-    TYPE_ORDERING
-      .iter()
-      .map(|t| {
-        (
-          *t,
-          type_effectiveness[t][&a]
-        )
-      })
-      .collect::<HashMap<_, _>>()
-  }
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::alloc::{GlobalAlloc, Layout, System};
+  use std::cell::Cell;
 
-  pub fn defender_dual_type_effectiveness(&self, a: Type, b: Type) -> HashMap<Type, f64> {
-    Mechanics::defender_dual_type_effectiveness_internal(&self.type_effectiveness, a, b)
+  // Counts allocations per thread, so tests running alongside don't interfere
+  struct CountingAllocator;
+
+  thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
   }
 
-  fn defender_dual_type_effectiveness_internal(type_effectiveness: &HashMap<Type, HashMap<Type, f64>>, a: Type, b: Type) -> HashMap<Type, f64> {
-    TYPE_ORDERING
-      .iter()
-      .map(|t| {
-        (
-          *t,
-          type_effectiveness[t][&a] * type_effectiveness[t][&b],
-        )
-      })
-      .collect::<HashMap<_, _>>()
+  unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+      let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+      System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+      System.dealloc(ptr, layout)
+    }
   }
-}
 
-#[cfg(test)]
-mod tests {
-  use super::*;
+  #[global_allocator]
+  static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+  fn allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCATIONS.with(Cell::get);
+    let result = f();
+    (result, ALLOCATIONS.with(Cell::get) - before)
+  }
 
   #[test]
   fn test_type_effectiveness() {
@@ -349,7 +569,7 @@ mod tests {
 
     let regi = mech.pokemon_instance(
       "REGISTEEL",
-      Level { level: 22, a_half: true },
+      Level::new(22, true).unwrap(),
       15, 2, 5,
       "LOCK_ON_FAST",
       "FOCUS_BLAST",
@@ -358,16 +578,204 @@ mod tests {
 
     let machamp = mech.pokemon_instance(
       "MACHAMP",
-      Level { level: 22, a_half: true },
+      Level::new(22, true).unwrap(),
       15, 2, 5,
       "COUNTER_FAST",
       "DYNAMIC_PUNCH",
       None
     ).unwrap();
 
-    // TODO transpose effectiveness matrix, these all fail
     assert!(machamp.type_effectiveness(&regi.charged_move2) == 1.);
     assert!(regi.type_effectiveness(&regi.fast_move) < 1.);
     assert!(regi.type_effectiveness(&regi.charged_move1) > 1.);
   }
+
+  #[test]
+  fn test_forms() {
+    let mech = Mechanics::from_gamemaster(&gm::test_fixture()).unwrap();
+
+    let marowak = mech.pokemon("MAROWAK").unwrap();
+    assert_eq!(marowak.form, None);
+    assert_eq!(marowak.type1, Type::Ground);
+
+    let alolan = mech.pokemon(("MAROWAK", "MAROWAK_ALOLA")).unwrap();
+    assert_eq!((alolan.type1, alolan.type2), (Type::Fire, Some(Type::Ghost)));
+    assert!(alolan.charged_moves.contains_key("SHADOW_BONE"));
+    assert_eq!(mech.pokemon(("MAROWAK", "ALOLA")).unwrap().form, alolan.form);
+
+    // No form-less entry: the first form in formSettings is the default
+    let stunfisk = mech.pokemon("STUNFISK").unwrap();
+    assert_eq!(stunfisk.form.as_deref(), Some("STUNFISK_NORMAL"));
+    assert_eq!(mech.forms("STUNFISK"), &["STUNFISK_NORMAL", "STUNFISK_GALARIAN"]);
+    assert!(mech.forms("MEDICHAM").is_empty());
+    assert!(mech.pokemon(("STUNFISK", "ALOLA")).is_none());
+
+    let galarian = mech.pokemon_instance(
+      &SpeciesId::new("STUNFISK", Some("STUNFISK_GALARIAN")),
+      Level::new(24, false).unwrap(),
+      0, 15, 15,
+      "MUD_SHOT_FAST",
+      "ROCK_SLIDE",
+      Some("EARTHQUAKE"),
+    ).unwrap();
+    assert_eq!(galarian.pokemon.type2, Some(Type::Steel));
+    assert!(mech.pokemon_instance(
+      "STUNFISK",
+      Level::new(24, false).unwrap(),
+      0, 15, 15,
+      "MUD_SHOT_FAST",
+      "ROCK_SLIDE",
+      Some("EARTHQUAKE"),
+    ).is_err());
+  }
+
+  #[test]
+  fn test_evolutions() {
+    let mech = Mechanics::from_gamemaster(&gm::test_fixture()).unwrap();
+
+    assert_eq!(
+      mech.evolutions_of("MEDITITE"),
+      &[Evolution { species: SpeciesId::from("MEDICHAM"), candy_cost: Some(50), item: None }]
+    );
+    // Mega evolution branches aren't species evolutions
+    assert!(mech.evolutions_of("MEDICHAM").is_empty());
+    assert_eq!(mech.pre_evolutions_of("MEDICHAM"), vec![SpeciesId::from("MEDITITE")]);
+    assert!(mech.pre_evolutions_of("MEDITITE").is_empty());
+    assert_eq!(
+      mech.family_of("MEDICHAM"),
+      &[SpeciesId::from("MEDITITE"), SpeciesId::from("MEDICHAM")]
+    );
+
+    let cubone = mech.evolutions_of("CUBONE");
+    assert_eq!(cubone.len(), 2);
+    assert_eq!(cubone[1].species, SpeciesId::from(("MAROWAK", "MAROWAK_ALOLA")));
+    assert_eq!(mech.pre_evolutions_of(("MAROWAK", "ALOLA")), vec![SpeciesId::from("CUBONE")]);
+    assert_eq!(mech.family_of(("MAROWAK", "ALOLA")).len(), 4);
+
+    assert!(mech.evolutions_of("MISSINGNO").is_empty());
+    assert!(mech.family_of("MISSINGNO").is_empty());
+  }
+
+  #[test]
+  fn test_power_up_cost() {
+    let mech = Mechanics::from_gamemaster(&gm::test_fixture()).unwrap();
+    let machamp = |variant| mech.pokemon_variant_instance(
+      "MACHAMP", variant, Level::new(20, false).unwrap(),
+      15, 15, 15,
+      "COUNTER_FAST",
+      "DYNAMIC_PUNCH",
+      None,
+    ).unwrap();
+    let l30 = Level::new(30, false).unwrap();
+
+    assert_eq!(
+      mech.power_up_cost(&machamp(Variant::Normal), l30, false, false).unwrap(),
+      PowerUpCost { stardust: 75000, candy: 66, xl_candy: 0 }
+    );
+    assert_eq!(
+      mech.power_up_cost(&machamp(Variant::Normal), l30, true, false).unwrap(),
+      PowerUpCost { stardust: 37500, candy: 66, xl_candy: 0 }
+    );
+    assert_eq!(
+      mech.power_up_cost(&machamp(Variant::Purified), l30, true, false).unwrap(),
+      PowerUpCost { stardust: 33750, candy: 66, xl_candy: 0 }
+    );
+    assert_eq!(
+      mech.power_up_cost(&machamp(Variant::Shadow), l30, false, true).unwrap(),
+      PowerUpCost { stardust: 90000 + 60000, candy: 86 + 60, xl_candy: 0 }
+    );
+    assert!(mech.power_up_cost(&machamp(Variant::Shadow), l30, true, false).is_err());
+  }
+
+  #[test]
+  fn test_xl_levels() {
+    let mech = Mechanics::from_gamemaster(&gm::test_fixture()).unwrap();
+    assert_eq!(mech.max_level(), Level::new(55, false).unwrap());
+    assert_eq!(mech.level_cap, Level::MAX_BEST_BUDDY);
+    assert!((mech.cp_multiplier(&Level::new(40, true).unwrap()).unwrap() - 0.792803968).abs() < 1e-6);
+    assert!((mech.cp_multiplier(&Level::new(50, false).unwrap()).unwrap() - 0.84029999).abs() < 1e-9);
+    assert!((mech.cp_multiplier(&Level::new(51, false).unwrap()).unwrap() - 0.84529999).abs() < 1e-9);
+
+    assert_eq!(
+      Level::MAX_POWER_UP.add_capped(3, Level::MAX_BEST_BUDDY),
+      Level::MAX_BEST_BUDDY
+    );
+    assert_eq!(Level::MAX_BEST_BUDDY.next_capped(Level::MAX_POWER_UP), Level::MAX_BEST_BUDDY);
+
+    let registeel = |mech: &Mechanics, level| mech.pokemon_instance(
+      "REGISTEEL", level,
+      15, 15, 15,
+      "LOCK_ON_FAST",
+      "FOCUS_BLAST",
+      None,
+    );
+    let l40 = Level::new(40, false).unwrap();
+    assert!(registeel(&mech, Level::MAX_BEST_BUDDY).is_ok());
+    assert!(registeel(&mech, Level::MAX_BEST_BUDDY.next()).is_err());
+    assert_eq!(
+      mech.power_up_cost(&registeel(&mech, l40).unwrap(), Level::MAX_POWER_UP, false, false).unwrap(),
+      PowerUpCost { stardust: 232000, candy: 0, xl_candy: 296 }
+    );
+
+    let no_xl = mech.with_level_cap(l40).unwrap();
+    assert!(registeel(&no_xl, l40).is_ok());
+    assert!(registeel(&no_xl, l40.next()).is_err());
+
+    // A GameMaster from before XL candy only knows up to level 40
+    let mut old_gm = gm::test_fixture();
+    for i in old_gm.item_templates.iter_mut() {
+      if let Some(gm::GameMasterEntry::PlayerLevel(pl)) = &mut i.entry {
+        pl.cp_multiplier.truncate(40);
+      }
+    }
+    let old = Mechanics::from_gamemaster(&old_gm).unwrap();
+    assert_eq!((old.max_level(), old.level_cap), (l40, l40));
+    assert!(old.cp_multiplier(&l40.next()).is_err());
+    assert!(registeel(&old, l40.next()).is_err());
+    assert!(old.with_level_cap(Level::MAX_BEST_BUDDY).is_err());
+  }
+
+  #[test]
+  fn test_shared_lookups() {
+    let mech = Mechanics::from_gamemaster(&gm::test_fixture()).unwrap();
+    let registeel = |level| mech.pokemon_instance(
+      "REGISTEEL", level,
+      15, 15, 15,
+      "LOCK_ON_FAST",
+      "FOCUS_BLAST",
+      Some("FLASH_CANNON"),
+    ).unwrap();
+    let (a, b) = (registeel(Level::MIN), registeel(Level::MAX_POWER_UP));
+
+    // Instances point at the Mechanics' species and moves rather than copies
+    assert!(Arc::ptr_eq(&a.pokemon, &b.pokemon));
+    assert!(Arc::ptr_eq(&a.pokemon, &mech.pokemon("REGISTEEL").unwrap()));
+    assert!(Arc::ptr_eq(&a.fast_move, &mech.fast_move("LOCK_ON_FAST").unwrap()));
+    assert!(Arc::ptr_eq(&a.charged_move2, &mech.charged_move("FLASH_CANNON").unwrap()));
+    assert!(Arc::ptr_eq(&a.pokemon.charged_moves["FOCUS_BLAST"], &b.charged_move1));
+
+    assert!(mech.fast_move("FLASH_CANNON").is_none());
+    assert!(mech.charged_move("FLASH_CANNON").is_some());
+  }
+
+  #[test]
+  fn test_lookups_dont_allocate() {
+    let mech = Mechanics::from_gamemaster(&gm::test_fixture()).unwrap();
+    let registeel = || mech.pokemon_instance(
+      "REGISTEEL", Level::MIN,
+      15, 15, 15,
+      "LOCK_ON_FAST",
+      "FOCUS_BLAST",
+      Some("FLASH_CANNON"),
+    );
+    let alolan = SpeciesId::from(("MAROWAK", "ALOLA"));
+
+    // Plain names, full forms, form suffixes and the default form fallback
+    assert_eq!(allocations(|| registeel().is_ok()), (true, 0));
+    assert_eq!(allocations(|| mech.pokemon(("MAROWAK", "MAROWAK_ALOLA")).is_some()), (true, 0));
+    assert_eq!(allocations(|| mech.pokemon(&alolan).is_some()), (true, 0));
+    assert_eq!(allocations(|| mech.pokemon("STUNFISK").is_some()), (true, 0));
+    assert_eq!(allocations(|| mech.evolutions_of("CUBONE").len()), (2, 0));
+    assert_eq!(allocations(|| mech.family_of(("MAROWAK", "ALOLA")).len()), (4, 0));
+  }
 }
@@ -1,16 +1,35 @@
 use std::convert::TryFrom;
 
 mod battle;
+mod breakpoints;
+mod coverage;
+mod inference;
+mod league;
 mod mechanics;
 mod moves;
+mod move_stats;
 mod pokemon;
+mod settings;
+mod type_chart;
 
 use crate::error::*;
 use pokemon::*;
 
 pub use mechanics::Mechanics;
-pub use battle::Battle;
-pub use pokemon::{PokemonInstance, Level};
+pub use battle::{
+  Action, AlwaysShield, Battle, BattleResult, BattleState, BattleStrategy, BuffProcs, ChargedChoice,
+  DefaultStrategy, Faint, InOrder, IncomingCharged, NeverShield, SaveLastShield, ShieldPolicy, ShieldThreshold,
+  Shields, SwitchPolicy, TeamBattle, TeamBattleResult, Tiebreak, TurnState,
+};
+pub use breakpoints::{Breakpoint, Build, Bulkpoint};
+pub use coverage::{type_combinations, Coverage, MoveCoverage, TeamCoverage, TypeCombination, Weaknesses};
+pub use inference::{Candidate, Observation, Stat};
+pub use league::League;
+pub use pokemon::{Evolution, Iv, Level, Pokemon, PokemonInstance, SpeciesId, SpeciesKey, Variant};
+pub use moves::{FastMove, ChargedMove, MoveBuffs};
+pub use move_stats::{ChargedMoveStats, FastMoveStats, MoveCounts, MovepoolStats};
+pub use settings::{CombatSettings, PowerUpCost, StatStageSettings, UpgradeSettings};
+pub use type_chart::{TypeChart, TypeMultipliers};
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum Type {
@@ -86,23 +105,53 @@ impl TryFrom<&str> for Type {
 mod tests {
 
   use super::*;
-
-  use crate::gamemaster::GameMaster;
+  use crate::model::moves::Damage;
 
   #[test]
   fn test_level_conversion() {
-    let l: u16 = (&Level {
-      level: 40,
-      a_half: false,
-    })
-      .into();
+    let l: u16 = (&Level::new(40, false).unwrap()).into();
     assert_eq!(l, 78);
-    let l: u16 = (&Level {
-      level: 27,
-      a_half: true,
-    })
-      .into();
+    let l: u16 = (&Level::new(27, true).unwrap()).into();
     assert_eq!(l, 53);
+
+    assert_eq!(Level::try_from(53).unwrap(), Level::new(27, true).unwrap());
+    assert_eq!(Level::try_from(108).unwrap(), Level::MAX);
+    assert!(Level::try_from(109).is_err());
+    assert_eq!(Level::MAX.next(), Level::MAX);
+  }
+
+  #[test]
+  fn test_level_and_iv_parsing() {
+    assert_eq!("27.5".parse::<Level>().unwrap(), Level::new(27, true).unwrap());
+    assert_eq!("40".parse::<Level>().unwrap(), Level::new(40, false).unwrap());
+    assert_eq!(" 51.0 ".parse::<Level>().unwrap(), Level::MAX_BEST_BUDDY);
+    assert_eq!(Level::new(27, true).unwrap().to_string(), "27.5");
+    assert_eq!(Level::MAX_POWER_UP.to_string(), "50");
+
+    for bad in &["0", "0.5", "55.5", "56", "27.3", "27.", "abc", "-1", ""] {
+      assert!(bad.parse::<Level>().is_err(), "{:?} should not parse", bad);
+    }
+    assert!(matches!("27.3".parse::<Level>(), Err(Error::ParseError(_))));
+    assert!(matches!("56".parse::<Level>(), Err(Error::BoundsError(_))));
+
+    assert_eq!("15".parse::<Iv>().unwrap(), Iv::MAX);
+    assert_eq!(Iv::new(7).unwrap().to_string(), "7");
+    assert_eq!(Iv::all().count(), 16);
+    assert!(matches!("16".parse::<Iv>(), Err(Error::BoundsError(_))));
+    assert!(matches!("x".parse::<Iv>(), Err(Error::ParseError(_))));
+    assert!(Iv::try_from(16).is_err());
+
+    let mech = Mechanics::from_gamemaster(&crate::gamemaster::test_fixture()).unwrap();
+    let altaria = |level, atk_iv| mech.pokemon_instance(
+      "ALTARIA", level,
+      atk_iv, 15, 15,
+      "DRAGON_BREATH_FAST",
+      "SKY_ATTACK",
+      None,
+    );
+    assert!(altaria(Level::MIN, 16).is_err());
+    assert!(altaria(Level::MAX, 15).is_err());
+    assert!(mech.cp_multiplier(&Level::MAX).is_ok());
   }
 
   #[test]
@@ -129,10 +178,7 @@ mod tests {
     // Altaria lv28 6/13/14
     let altaria = mech.pokemon_instance(
       "ALTARIA",
-      Level {
-        level: 28,
-        a_half: false,
-      },
+      Level::new(28, false).unwrap(),
       6,
       13,
       14,
@@ -144,10 +190,7 @@ mod tests {
 
     let noctowl = mech.pokemon_instance(
       "NOCTOWL",
-      Level {
-        level: 28,
-        a_half: false,
-      },
+      Level::new(28, false).unwrap(),
       5,
       11,
       12,
@@ -159,10 +202,7 @@ mod tests {
 
     let charizard = mech.pokemon_instance(
       "CHARIZARD",
-      Level {
-        level: 18,
-        a_half: true,
-      },
+      Level::new(18, true).unwrap(),
       11,
       8,
       15,
@@ -176,4 +216,53 @@ mod tests {
     assert_eq!(noctowl.cp(), 1491);
     assert_eq!(charizard.cp(), 1473);
   }
+
+  #[test]
+  fn test_variants() {
+    let mech = Mechanics::from_gamemaster(&crate::gamemaster::test_fixture()).unwrap();
+    let level = Level::new(20, false).unwrap();
+    let machamp = |variant, charged_move| mech.pokemon_variant_instance(
+      "MACHAMP", variant, level,
+      15, 15, 15,
+      "COUNTER_FAST",
+      charged_move,
+      None,
+    );
+
+    let normal = machamp(Variant::Normal, "DYNAMIC_PUNCH").unwrap();
+    let shadow = machamp(Variant::Shadow, "FRUSTRATION").unwrap();
+    let purified = machamp(Variant::Purified, "RETURN").unwrap();
+
+    assert_eq!(shadow.variant(), Variant::Shadow);
+    assert_eq!(shadow.cp(), normal.cp());
+    assert!((shadow.attack() / normal.attack() - 1.2).abs() < 1e-6);
+    assert!((shadow.defense() / normal.defense() - 0.8333333).abs() < 1e-6);
+    assert_eq!(purified.attack(), normal.attack());
+
+    assert!(machamp(Variant::Normal, "FRUSTRATION").is_err());
+    assert!(machamp(Variant::Normal, "RETURN").is_err());
+    assert!(machamp(Variant::Shadow, "RETURN").is_err());
+    assert!(machamp(Variant::Purified, "FRUSTRATION").is_err());
+    assert!(mech.pokemon_variant_instance(
+      "REGISTEEL", Variant::Shadow, level,
+      15, 15, 15,
+      "LOCK_ON_FAST",
+      "FLASH_CANNON",
+      None,
+    ).is_err());
+
+    let settings = &mech.combat_settings;
+    assert!(
+      shadow.fast_move.calculate(&shadow, &normal, settings) >
+      normal.fast_move.calculate(&normal, &normal, settings)
+    );
+    assert!(
+      normal.fast_move.calculate(&normal, &shadow, settings) >
+      normal.fast_move.calculate(&normal, &normal, settings)
+    );
+
+    assert_eq!(mech.upgrades.stardust_multiplier(Variant::Normal), 1.);
+    assert_eq!(mech.upgrades.stardust_multiplier(Variant::Shadow), 1.2);
+    assert_eq!(mech.upgrades.candy_multiplier(Variant::Purified), 0.9);
+  }
 }
@@ -0,0 +1,214 @@
+use crate::error::*;
+use crate::model::mechanics::Mechanics;
+use crate::model::moves::*;
+use crate::model::pokemon::*;
+use crate::model::Type;
+
+// ==================
+// === Move stats ===
+// ==================
+
+// Damage here is power scaled by STAB and, if an opponent is given, type
+// effectiveness; attack and defense stats are left out so moves compare across
+// species.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FastMoveStats {
+  pub move_id: String,
+  pub type_: Type,
+  pub turns: i32,
+  pub damage: f64,
+  pub energy: i16,
+  pub dpt: f64,
+  pub ept: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChargedMoveStats {
+  pub move_id: String,
+  pub type_: Type,
+  pub damage: f64,
+  // Energy cost, as a positive number
+  pub energy: i16,
+  pub dpe: f64,
+}
+
+// Fast moves needed before each charged move in a row, e.g. 8-7-7, and the
+// energy left over after each one. Ends once the leftover repeats, after which
+// the counts repeat too (from the start when it is back to 0).
+#[derive(Debug, Clone, PartialEq)]
+pub struct MoveCounts {
+  pub fast_move_id: String,
+  pub charged_move_id: String,
+  pub counts: Vec<u32>,
+  pub leftover: Vec<i16>,
+}
+
+impl MoveCounts {
+  pub fn new(fast_move: &FastMove, charged_move: &ChargedMove, max_energy: i16) -> MoveCounts {
+    let mut counts = MoveCounts {
+      fast_move_id: fast_move.uid.clone(),
+      charged_move_id: charged_move.uid.clone(),
+      counts: Vec::new(),
+      leftover: Vec::new(),
+    };
+    let (gain, cost) = (fast_move.energy, -charged_move.energy);
+    if gain <= 0 || cost <= 0 || cost > max_energy {
+      return counts;
+    }
+
+    let mut energy = 0;
+    // The leftover is below `gain`, so it repeats within `gain` charged moves
+    for _ in 0..=gain {
+      let mut uses = 0;
+      while energy < cost {
+        energy = std::cmp::min(energy + gain, max_energy);
+        uses += 1;
+      }
+      energy -= cost;
+      let repeats = energy == 0 || counts.leftover.contains(&energy);
+      counts.counts.push(uses);
+      counts.leftover.push(energy);
+      if repeats {
+        break;
+      }
+    }
+    counts
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MovepoolStats {
+  pub pokemon_id: String,
+  pub fast_moves: Vec<FastMoveStats>,
+  pub charged_moves: Vec<ChargedMoveStats>,
+  // Every fast move against every charged move
+  pub move_counts: Vec<MoveCounts>,
+}
+
+impl Mechanics {
+  fn damage_multiplier<M: Damage>(&self, pokemon: &Pokemon, move_: &M, opponent: Option<&[Type]>) -> f64 {
+    let stab = if move_.stab(pokemon) { self.combat_settings.same_type_attack_bonus } else { 1. };
+    let effectiveness = opponent.map_or(1., |o| self.type_effectiveness.defend(o)[*move_.type_()]);
+    stab * effectiveness
+  }
+
+  pub fn fast_move_stats(&self, pokemon: &Pokemon, fast_move: &FastMove, opponent: Option<&[Type]>) -> FastMoveStats {
+    let damage = fast_move.power * self.damage_multiplier(pokemon, fast_move, opponent);
+    let turns = fast_move.duration();
+    FastMoveStats {
+      move_id: fast_move.uid.clone(),
+      type_: fast_move.type_,
+      turns,
+      damage,
+      energy: fast_move.energy,
+      dpt: damage / turns as f64,
+      ept: fast_move.energy as f64 / turns as f64,
+    }
+  }
+
+  pub fn charged_move_stats(&self, pokemon: &Pokemon, charged_move: &ChargedMove, opponent: Option<&[Type]>) -> ChargedMoveStats {
+    let damage = charged_move.power * self.damage_multiplier(pokemon, charged_move, opponent);
+    let energy = -charged_move.energy;
+    ChargedMoveStats {
+      move_id: charged_move.uid.clone(),
+      type_: charged_move.type_,
+      damage,
+      energy,
+      dpe: damage / energy as f64,
+    }
+  }
+
+  // The species' regular movepool, sorted by move id
+  pub fn movepool_stats<S: Into<SpeciesId>>(&self, species: S, opponent: Option<&[Type]>) -> Result<MovepoolStats, Error> {
+    let species = species.into();
+    let pok = self
+      .pokemon(&species)
+      .ok_or_else(|| Error::BoundsError(format!("Could not find pokemon {}", species)))?;
+
+    let mut fast_moves: Vec<&FastMove> = pok.fast_moves.values().map(|m| m.as_ref()).collect();
+    fast_moves.sort_by(|a, b| a.uid.cmp(&b.uid));
+    let mut charged_moves: Vec<&ChargedMove> = pok.charged_moves.values().map(|m| m.as_ref()).collect();
+    charged_moves.sort_by(|a, b| a.uid.cmp(&b.uid));
+
+    Ok(MovepoolStats {
+      pokemon_id: pok.id.clone(),
+      fast_moves: fast_moves
+        .iter()
+        .map(|m| self.fast_move_stats(&pok, m, opponent))
+        .collect(),
+      charged_moves: charged_moves
+        .iter()
+        .map(|m| self.charged_move_stats(&pok, m, opponent))
+        .collect(),
+      move_counts: fast_moves
+        .iter()
+        .flat_map(|f| {
+          charged_moves
+            .iter()
+            .map(move |c| MoveCounts::new(f, c, self.combat_settings.max_energy))
+        })
+        .collect(),
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::gamemaster::test_fixture;
+
+  fn close(a: f64, b: f64) -> bool {
+    (a - b).abs() < 1e-9
+  }
+
+  #[test]
+  fn test_move_counts() {
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
+    let counter = mech.fast_move("COUNTER_FAST").unwrap();
+    let dynamic_punch = mech.charged_move("DYNAMIC_PUNCH").unwrap();
+
+    // 7 energy per Counter into 50 energy Dynamic Punches
+    let counts = MoveCounts::new(&counter, &dynamic_punch, 100);
+    assert_eq!(counts.counts, vec![8, 7, 7, 7, 7, 7, 7]);
+    assert_eq!(counts.leftover, vec![6, 5, 4, 3, 2, 1, 0]);
+
+    // Energy is capped, so overflowing moves need more uses than the division says
+    let capped = MoveCounts::new(&counter, &dynamic_punch, 52);
+    assert_eq!(capped.counts, vec![8, 7, 7]);
+    assert_eq!(capped.leftover, vec![2, 1, 0]);
+
+    assert!(MoveCounts::new(&counter, &dynamic_punch, 40).counts.is_empty());
+  }
+
+  #[test]
+  fn test_movepool_stats() {
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
+    let machamp = mech.movepool_stats("MACHAMP", None).unwrap();
+
+    let counter = &machamp.fast_moves[0];
+    assert_eq!((counter.move_id.as_str(), counter.turns, counter.energy), ("COUNTER_FAST", 2, 7));
+    assert!(close(counter.damage, 8. * 1.2));
+    assert!(close(counter.dpt, 8. * 1.2 / 2.));
+    assert!(close(counter.ept, 3.5));
+
+    let ids: Vec<&str> = machamp.charged_moves.iter().map(|m| m.move_id.as_str()).collect();
+    assert_eq!(ids, vec!["CLOSE_COMBAT", "DYNAMIC_PUNCH", "ICE_PUNCH"]);
+    let dynamic_punch = &machamp.charged_moves[1];
+    assert_eq!(dynamic_punch.energy, 50);
+    assert!(close(dynamic_punch.dpe, dynamic_punch.damage / 50.));
+    // No STAB on Ice Punch
+    let ice_punch = &machamp.charged_moves[2];
+    assert!(close(ice_punch.damage, mech.charged_move("ICE_PUNCH").unwrap().power));
+    assert_eq!(machamp.move_counts.len(), machamp.fast_moves.len() * machamp.charged_moves.len());
+
+    // Fighting is super effective against normal types, ice isn't
+    let vs_normal = mech.movepool_stats("MACHAMP", Some(&[Type::Normal])).unwrap();
+    assert!(close(vs_normal.fast_moves[0].damage, counter.damage * 1.6));
+    assert!(close(vs_normal.charged_moves[2].damage, ice_punch.damage));
+    // Against steel/flying the two cancel out
+    let vs_skarmory = mech.movepool_stats("MACHAMP", Some(&[Type::Steel, Type::Flying])).unwrap();
+    assert!(close(vs_skarmory.fast_moves[0].damage, counter.damage));
+    assert!(mech.movepool_stats("MISSINGNO", None).is_err());
+  }
+}
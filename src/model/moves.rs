@@ -2,21 +2,76 @@ use crate::error::*;
 use crate::gamemaster as gm;
 use crate::model::Type;
 use crate::model::pokemon::*;
+use crate::model::settings::CombatSettings;
 
 use std::convert::TryFrom;
+use std::sync::Arc;
 
 // ====================
 // === Damage trait ===
 // ====================
 
-// Floor(0.5 ∗ Power ∗ Atk / Def ∗ STAB ∗ Effective) + 1
+// Floor(Bonus ∗ 0.5 ∗ Power ∗ Atk / (Def ∗ defense_bonus) ∗ STAB ∗ Effective ∗ Purified) + 1
+// where, from the CombatSettings, Bonus is fast_attack_bonus or
+// charged_attack_bonus, STAB is same_type_attack_bonus, and Purified is
+// purified_attack_vs_shadow when a purified Pokémon hits a shadow one. Atk and
+// Def already include shadow_attack_bonus and shadow_defense_bonus.
 // https://pokemongohub.net/post/questions-and-answers/move-damage-output-actually-calculated/
 pub trait Damage {
-  fn calculate(&self, source: &PokemonInstance, target: &PokemonInstance) -> i16;
+  fn calculate(&self, source: &PokemonInstance, target: &PokemonInstance, settings: &CombatSettings) -> i16;
   fn type_(&self) -> &Type;
   fn stab(&self, p: &Pokemon) -> bool;
 }
 
+// Instances share their moves with the Mechanics
+impl<M: Damage> Damage for Arc<M> {
+  fn calculate(&self, source: &PokemonInstance, target: &PokemonInstance, settings: &CombatSettings) -> i16 {
+    M::calculate(self, source, target, settings)
+  }
+
+  fn type_(&self) -> &Type {
+    M::type_(self)
+  }
+
+  fn stab(&self, p: &Pokemon) -> bool {
+    M::stab(self, p)
+  }
+}
+
+// =================
+// === MoveBuffs ===
+// =================
+
+// Stat stage changes a move may apply when it hits, and the odds it does
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MoveBuffs {
+  pub attacker_attack: i8,
+  pub attacker_defense: i8,
+  pub target_attack: i8,
+  pub target_defense: i8,
+  pub chance: f64,
+}
+
+impl TryFrom<&gm::PvPMoveBuffs> for MoveBuffs {
+  type Error = Error;
+
+  fn try_from(b: &gm::PvPMoveBuffs) -> Result<Self, Self::Error> {
+    let stage = |s: i32| {
+      i8::try_from(s).map_err(|e| {
+        Error::ConversionError(format!("Can't convert stat stage change {}: {:?}", s, e))
+      })
+    };
+
+    Ok(MoveBuffs {
+      attacker_attack: stage(b.attacker_attack_stat_stage_change)?,
+      attacker_defense: stage(b.attacker_defense_stat_stage_change)?,
+      target_attack: stage(b.target_attack_stat_stage_change)?,
+      target_defense: stage(b.target_defense_stat_stage_change)?,
+      chance: b.buff_activation_chance,
+    })
+  }
+}
+
 // ================
 // === FastMove ===
 // ================
@@ -28,6 +83,7 @@ pub struct FastMove {
   pub power: f64,
   pub turns: i32,
   pub energy: i16,
+  pub buffs: Option<MoveBuffs>,
 }
 
 impl TryFrom<&gm::PvPMove> for FastMove {
@@ -43,6 +99,7 @@ impl TryFrom<&gm::PvPMove> for FastMove {
         power: s.power,
         turns: s.duration_turns,
         energy: s.energy_delta as _,
+        buffs: s.buffs.as_ref().map(MoveBuffs::try_from).transpose()?,
       })
     } else {
       Err(Error::ConversionError(format!(
@@ -61,7 +118,14 @@ impl TryFrom<gm::PvPMove> for FastMove {
   }
 }
 
-// Floor(0.5 ∗ Power ∗ Atk / Def ∗ STAB ∗ Effective) + 1
+impl FastMove {
+  // `turns` counts the turns in excess of the first one
+  pub fn duration(&self) -> i32 {
+    self.turns + 1
+  }
+}
+
+// See `Damage`, with fast_attack_bonus
 impl Damage for FastMove {
   fn stab(&self, p: &Pokemon) -> bool {
     p.type1 == self.type_ || (p.type2.is_some() && p.type2.unwrap() == self.type_)
@@ -71,17 +135,23 @@ impl Damage for FastMove {
     &self.type_
   }
 
-  fn calculate(&self, source: &PokemonInstance, target: &PokemonInstance) -> i16 {
-    let stab = if source.stab(self) { 1.2 } else { 1.0 };
+  fn calculate(&self, source: &PokemonInstance, target: &PokemonInstance, settings: &CombatSettings) -> i16 {
+    let stab = if source.stab(self) { settings.same_type_attack_bonus } else { 1.0 };
     let effectiveness = target.type_effectiveness(self);
+    let purified = if source.variant() == Variant::Purified && target.variant() == Variant::Shadow {
+      settings.purified_attack_vs_shadow
+    } else {
+      1.0
+    };
     (
       (
-        1.3 *
+        settings.fast_attack_bonus *
         0.5 *
         self.power *
-        (source.attack() / target.defense()) *
+        (source.attack() / (target.defense() * settings.defense_bonus)) *
         stab *
-        effectiveness
+        effectiveness *
+        purified
       ).floor() + 1.0
     ).round() as _
   }
@@ -97,6 +167,7 @@ pub struct ChargedMove {
   pub type_: Type,
   pub power: f64,
   pub energy: i16,
+  pub buffs: Option<MoveBuffs>,
 }
 
 impl TryFrom<&gm::PvPMove> for ChargedMove {
@@ -114,6 +185,7 @@ impl TryFrom<&gm::PvPMove> for ChargedMove {
         })?,
         power: s.power,
         energy: s.energy_delta as _,
+        buffs: s.buffs.as_ref().map(MoveBuffs::try_from).transpose()?,
       })
     } else {
       Err(Error::ConversionError(format!(
@@ -132,7 +204,7 @@ impl TryFrom<gm::PvPMove> for ChargedMove {
   }
 }
 
-
+// See `Damage`, with charged_attack_bonus
 impl Damage for ChargedMove {
   fn stab(&self, p: &Pokemon) -> bool {
     p.type1 == self.type_ || (p.type2.is_some() && p.type2.unwrap() == self.type_)
@@ -142,18 +214,65 @@ impl Damage for ChargedMove {
     &self.type_
   }
 
-  fn calculate(&self, source: &PokemonInstance, target: &PokemonInstance) -> i16 {
-    let stab = if source.stab(self) { 1.2 } else { 1.0 };
+  fn calculate(&self, source: &PokemonInstance, target: &PokemonInstance, settings: &CombatSettings) -> i16 {
+    let stab = if source.stab(self) { settings.same_type_attack_bonus } else { 1.0 };
     let effectiveness = target.type_effectiveness(self);
+    let purified = if source.variant() == Variant::Purified && target.variant() == Variant::Shadow {
+      settings.purified_attack_vs_shadow
+    } else {
+      1.0
+    };
     (
       (
-        1.3 *
+        settings.charged_attack_bonus *
         0.5 *
         self.power *
-        (source.attack() / target.defense()) *
+        (source.attack() / (target.defense() * settings.defense_bonus)) *
         stab *
-        effectiveness
+        effectiveness *
+        purified
       ).floor() + 1.0
     ).round() as _
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::gamemaster::test_fixture;
+  use crate::model::Mechanics;
+
+  #[test]
+  fn test_damage_uses_combat_settings() {
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
+
+    let attacker = mech.pokemon_instance(
+      "LUCARIO",
+      Level::new(21, false).unwrap(),
+      15, 0, 0,
+      "COUNTER_FAST",
+      "AURA_SPHERE",
+      Some("SHADOW_BALL"),
+    ).unwrap();
+
+    let defender = mech.pokemon_instance(
+      "LUCARIO",
+      Level::new(20, true).unwrap(),
+      0, 15, 15,
+      "COUNTER_FAST",
+      "AURA_SPHERE",
+      Some("SHADOW_BALL"),
+    ).unwrap();
+
+    let settings = mech.combat_settings;
+    assert_eq!(attacker.fast_move.calculate(&attacker, &defender, &settings), 16);
+    assert_eq!(attacker.charged_move1.calculate(&attacker, &defender, &settings), 180);
+
+    let no_bonus = CombatSettings { fast_attack_bonus: 1.0, charged_attack_bonus: 1.0, ..settings };
+    assert_eq!(attacker.fast_move.calculate(&attacker, &defender, &no_bonus), 13);
+    assert_eq!(attacker.charged_move1.calculate(&attacker, &defender, &no_bonus), 139);
+
+    let no_stab = CombatSettings { same_type_attack_bonus: 1.0, ..settings };
+    assert_eq!(attacker.fast_move.calculate(&attacker, &defender, &no_stab), 14);
+  }
+}
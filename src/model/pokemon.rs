@@ -1,62 +1,252 @@
 use std::collections::HashMap;
-use std::cmp::{PartialEq, Eq, PartialOrd, Ord, Ordering};
+use std::cmp::{PartialEq, Eq, PartialOrd, Ord};
+use std::convert::TryFrom;
+use std::fmt;
 use std::ops::{Add, Sub};
+use std::str::FromStr;
+use std::sync::Arc;
 
+use crate::error::*;
 use crate::gamemaster as gm;
 use crate::model::Type;
 use crate::model::moves::*;
+use crate::model::settings::{CombatSettings, PowerUpCost};
+use crate::model::type_chart::TypeMultipliers;
 
 // ===============
 // === Pokemon ===
 // ===============
 
+// === SpeciesId ===
+
+// A species and, optionally, one of its forms as named in the GameMaster
+// (e.g. "MAROWAK_ALOLA"). No form means the species' default form.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SpeciesId {
+  pub pokemon: String,
+  pub form: Option<String>,
+}
+
+impl SpeciesId {
+  pub fn new(pokemon: &str, form: Option<&str>) -> SpeciesId {
+    SpeciesId {
+      pokemon: pokemon.to_owned(),
+      form: form.map(str::to_owned),
+    }
+  }
+
+  pub fn key(&self) -> SpeciesKey<'_> {
+    SpeciesKey { pokemon: &self.pokemon, form: self.form.as_deref() }
+  }
+}
+
+impl From<&str> for SpeciesId {
+  fn from(pokemon: &str) -> SpeciesId {
+    SpeciesId::new(pokemon, None)
+  }
+}
+
+impl From<(&str, &str)> for SpeciesId {
+  fn from((pokemon, form): (&str, &str)) -> SpeciesId {
+    SpeciesId::new(pokemon, Some(form))
+  }
+}
+
+impl From<&SpeciesId> for SpeciesId {
+  fn from(s: &SpeciesId) -> SpeciesId {
+    s.clone()
+  }
+}
+
+impl std::fmt::Display for SpeciesId {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    self.key().fmt(f)
+  }
+}
+
+// === SpeciesKey ===
+
+// A borrowed SpeciesId, to look species up without allocating
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SpeciesKey<'a> {
+  pub pokemon: &'a str,
+  pub form: Option<&'a str>,
+}
+
+impl<'a> From<&'a str> for SpeciesKey<'a> {
+  fn from(pokemon: &'a str) -> SpeciesKey<'a> {
+    SpeciesKey { pokemon, form: None }
+  }
+}
+
+impl<'a> From<(&'a str, &'a str)> for SpeciesKey<'a> {
+  fn from((pokemon, form): (&'a str, &'a str)) -> SpeciesKey<'a> {
+    SpeciesKey { pokemon, form: Some(form) }
+  }
+}
+
+impl<'a> From<&'a SpeciesId> for SpeciesKey<'a> {
+  fn from(s: &'a SpeciesId) -> SpeciesKey<'a> {
+    s.key()
+  }
+}
+
+impl std::fmt::Display for SpeciesKey<'_> {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self.form {
+      Some(form) => write!(f, "{} ({})", self.pokemon, form),
+      None => write!(f, "{}", self.pokemon),
+    }
+  }
+}
+
+// === Evolution ===
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Evolution {
+  pub species: SpeciesId,
+  pub candy_cost: Option<u64>,
+  pub item: Option<String>,
+}
+
+impl Evolution {
+  pub fn from_branch(b: &gm::EvolutionBranch, ps: &gm::PokemonSettings) -> Option<Evolution> {
+    b.evolution.as_ref().map(|evolution| Evolution {
+      species: SpeciesId::new(evolution, b.form.as_deref()),
+      // Older GameMasters only have the species-wide candyToEvolve
+      candy_cost: b.candy_cost.or(ps.candy_to_evolve),
+      item: b.evolution_item_requirement.clone(),
+    })
+  }
+}
+
 #[derive(Debug, Clone)]
 pub struct Pokemon {
   pub id: String,
+  pub form: Option<String>,
+  pub family: String,
+  pub evolutions: Vec<Evolution>,
   pub stats: gm::Stats,
   pub type1: Type,
   pub type2: Option<Type>,
-  pub fast_moves: HashMap<String, FastMove>,
-  pub charged_moves: HashMap<String, ChargedMove>,
-  pub type_effectiveness: HashMap<Type, f64>,
+  pub fast_moves: HashMap<String, Arc<FastMove>>,
+  pub charged_moves: HashMap<String, Arc<ChargedMove>>,
+  // Against this species, per attacking type
+  pub type_effectiveness: TypeMultipliers,
+  // Only set for species that can be shadow: Frustration and Return
+  pub shadow_move: Option<Arc<ChargedMove>>,
+  pub purified_move: Option<Arc<ChargedMove>>,
+  // Unlocking the second charged move
+  pub third_move_cost: PowerUpCost,
 }
 
-// === Level ===
+impl Pokemon {
+  pub fn species(&self) -> SpeciesId {
+    SpeciesId::new(&self.id, self.form.as_deref())
+  }
 
-#[derive(PartialEq, Eq, PartialOrd, Copy, Clone, Debug)]
-pub struct Level {
-  pub level: u16,
-  pub a_half: bool,
-}
-
-impl Ord for Level {
-  fn cmp(&self, other: &Level) -> Ordering {
-    if self.level == other.level {
-      if self.a_half && !other.a_half {
-        Ordering::Greater
-      } else if !self.a_half && other.a_half {
-        Ordering::Less
-      } else {
-        Ordering::Equal
-      }
-    } else {
-      self.level.cmp(&other.level)
+  pub fn key(&self) -> SpeciesKey<'_> {
+    SpeciesKey { pokemon: &self.id, form: self.form.as_deref() }
+  }
+}
+
+// === Iv ===
+
+// An individual value, 0 to 15
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord, Copy, Clone, Debug, Default)]
+pub struct Iv(u16);
+
+impl Iv {
+  pub const MIN: Iv = Iv(0);
+  pub const MAX: Iv = Iv(15);
+
+  pub fn new(iv: u16) -> Result<Iv, Error> {
+    if iv > Iv::MAX.0 {
+      return Err(Error::BoundsError(format!("IV {} is above {}", iv, Iv::MAX)));
     }
+    Ok(Iv(iv))
+  }
+
+  // For constants known to be in range
+  pub(crate) const fn new_unchecked(iv: u16) -> Iv {
+    Iv(iv)
+  }
+
+  pub fn value(&self) -> u16 {
+    self.0
+  }
+
+  // Every IV from 0 to 15
+  pub fn all() -> impl Iterator<Item = Iv> {
+    (Iv::MIN.0..=Iv::MAX.0).map(Iv)
+  }
+}
+
+impl TryFrom<u16> for Iv {
+  type Error = Error;
+
+  fn try_from(iv: u16) -> Result<Self, Self::Error> {
+    Iv::new(iv)
   }
 }
 
+impl From<Iv> for u16 {
+  fn from(iv: Iv) -> u16 {
+    iv.0
+  }
+}
+
+impl FromStr for Iv {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let iv = s
+      .trim()
+      .parse::<u16>()
+      .map_err(|e| Error::ParseError(format!("Couldn't parse IV {:?}: {}", s, e)))?;
+    Iv::new(iv)
+  }
+}
+
+impl fmt::Display for Iv {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+// === Level ===
+
+// A Pokémon level from 1 to `Level::MAX`, in half level steps. Whether a
+// level is reachable also depends on the GameMaster's CPM table; see
+// `Mechanics::max_level` and `Mechanics::level_cap`.
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord, Copy, Clone, Debug)]
+pub struct Level {
+  level: u16,
+  a_half: bool,
+}
+
 impl From<&Level> for u16 {
   fn from(l: &Level) -> u16 {
     (l.level - 1) * 2 + (if l.a_half { 1 } else { 0 })
   }
 }
 
-impl From<u16> for Level {
-  fn from(l: u16) -> Level {
-    Level {
-      level: (l / 2) + 1,
-      a_half: l % 2 != 0,
+// From an index into the CPM table, i.e. the number of half levels above 1
+impl TryFrom<u16> for Level {
+  type Error = Error;
+
+  fn try_from(l: u16) -> Result<Self, Self::Error> {
+    if l > u16::from(&Level::MAX) {
+      return Err(Error::BoundsError(format!(
+        "Level index {} is above {}",
+        l,
+        Level::MAX
+      )));
     }
+    Ok(Level {
+      level: (l / 2) + 1,
+      a_half: !l.is_multiple_of(2),
+    })
   }
 }
 
@@ -84,9 +274,75 @@ impl Sub<usize> for Level {
   }
 }
 
+impl FromStr for Level {
+  type Err = Error;
+
+  // "27", "27.0" or "27.5"
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let parse_error = || Error::ParseError(format!("Couldn't parse level {:?}", s));
+    let (whole, fraction) = match s.trim().split_once('.') {
+      Some((whole, fraction)) => (whole, fraction),
+      None => (s.trim(), "0"),
+    };
+    let whole = whole.parse::<u16>().map_err(|_| parse_error())?;
+    let a_half = match fraction {
+      "0" => false,
+      "5" => true,
+      _ => return Err(parse_error()),
+    };
+    Level::new(whole, a_half)
+  }
+}
+
+impl fmt::Display for Level {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if self.a_half {
+      write!(f, "{}.5", self.level)
+    } else {
+      write!(f, "{}", self.level)
+    }
+  }
+}
+
 impl Level {
+  pub const MIN: Level = Level { level: 1, a_half: false };
+  // Highest level reachable by powering up, and with the best buddy boost
+  pub const MAX_POWER_UP: Level = Level { level: 50, a_half: false };
+  pub const MAX_BEST_BUDDY: Level = Level { level: 51, a_half: false };
+  // Highest level any GameMaster has a CPM for
+  pub const MAX: Level = Level { level: 55, a_half: false };
+
+  pub fn new(level: u16, a_half: bool) -> Result<Level, Error> {
+    let l = Level { level, a_half };
+    if level < Level::MIN.level || l > Level::MAX {
+      return Err(Error::BoundsError(format!(
+        "Level {} is not between {} and {}",
+        l,
+        Level::MIN,
+        Level::MAX
+      )));
+    }
+    Ok(l)
+  }
+
+  // For constants known to be in range
+  pub(crate) const fn new_unchecked(level: u16, a_half: bool) -> Level {
+    Level { level, a_half }
+  }
+
+  pub fn whole(&self) -> u16 {
+    self.level
+  }
+
+  pub fn is_half(&self) -> bool {
+    self.a_half
+  }
+
+  // Saturates at `Level::MAX`
   pub fn next(&self) -> Level {
-    if self.a_half {
+    if *self >= Level::MAX {
+      Level::MAX
+    } else if self.a_half {
       Level { level: self.level + 1, a_half: false }
     } else {
       Level { level: self.level, a_half: true }
@@ -102,25 +358,49 @@ impl Level {
       Level { level: std::cmp::max(1, self.level - 1), a_half: true }
     }
   }
+
+  // Like `next`, but never goes past `cap`
+  pub fn next_capped(&self, cap: Level) -> Level {
+    std::cmp::min(self.next(), std::cmp::max(*self, cap))
+  }
+
+  pub fn add_capped(self, steps: usize, cap: Level) -> Level {
+    (0..steps).fold(self, |lvl, _| lvl.next_capped(cap))
+  }
+}
+
+// === Variant ===
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Default)]
+pub enum Variant {
+  #[default]
+  Normal,
+  Shadow,
+  Purified,
 }
 
 // =======================
 // === PokemonInstance ===
 // =======================
 
+#[derive(Clone)]
 pub struct PokemonInstance {
-  pub pokemon: Pokemon,
+  pub pokemon: Arc<Pokemon>,
 
-  atk_iv: u16,
-  def_iv: u16,
-  sta_iv: u16,
+  atk_iv: Iv,
+  def_iv: Iv,
+  sta_iv: Iv,
 
   level: Level,
   cpm: f64,
 
-  pub fast_move: FastMove,
-  pub charged_move1: ChargedMove,
-  pub charged_move2: ChargedMove,
+  variant: Variant,
+  attack_multiplier: f64,
+  defense_multiplier: f64,
+
+  pub fast_move: Arc<FastMove>,
+  pub charged_move1: Arc<ChargedMove>,
+  pub charged_move2: Arc<ChargedMove>,
 }
 
 impl PokemonInstance {
@@ -131,32 +411,49 @@ impl PokemonInstance {
   // premature optimization
   //
   pub fn attack(&self) -> f64 {
-    (self.pokemon.stats.base_attack + self.atk_iv) as f64 * self.cpm
+    (self.pokemon.stats.base_attack + self.atk_iv.value()) as f64 * self.cpm * self.attack_multiplier
   }
 
   pub fn defense(&self) -> f64 {
-    (self.pokemon.stats.base_defense + self.def_iv) as f64 * self.cpm
+    (self.pokemon.stats.base_defense + self.def_iv.value()) as f64 * self.cpm * self.defense_multiplier
   }
 
   pub fn stamina(&self) -> f64 {
     f64::max(
       10.,
-      (self.pokemon.stats.base_stamina + self.sta_iv) as f64 * self.cpm,
+      (self.pokemon.stats.base_stamina + self.sta_iv.value()) as f64 * self.cpm,
     )
   }
 
+  // As shown in game
+  pub fn hp(&self) -> u32 {
+    self.stamina().floor() as u32
+  }
+
+  pub fn ivs(&self) -> (Iv, Iv, Iv) {
+    (self.atk_iv, self.def_iv, self.sta_iv)
+  }
+
   pub fn cp(&self) -> u32 {
-    let a = (self.pokemon.stats.base_attack + self.atk_iv) as f64;
-    let d = (self.pokemon.stats.base_defense + self.def_iv) as f64;
-    let s = (self.pokemon.stats.base_stamina + self.sta_iv) as f64;
+    let a = (self.pokemon.stats.base_attack + self.atk_iv.value()) as f64;
+    let d = (self.pokemon.stats.base_defense + self.def_iv.value()) as f64;
+    let s = (self.pokemon.stats.base_stamina + self.sta_iv.value()) as f64;
     let cpm = self.cpm;
     f64::floor(a * d.sqrt() * s.sqrt() * cpm * cpm / 10.) as u32
   }
 
+  pub fn level(&self) -> Level {
+    self.level
+  }
+
+  pub fn variant(&self) -> Variant {
+    self.variant
+  }
+
   pub fn stat_product(&self) -> f64 {
-    let a = self.cpm * (self.pokemon.stats.base_attack + self.atk_iv) as f64;
-    let d = self.cpm * (self.pokemon.stats.base_defense + self.def_iv) as f64;
-    let s = self.cpm * (self.pokemon.stats.base_stamina + self.sta_iv) as f64;
+    let a = self.cpm * (self.pokemon.stats.base_attack + self.atk_iv.value()) as f64;
+    let d = self.cpm * (self.pokemon.stats.base_defense + self.def_iv.value()) as f64;
+    let s = self.cpm * (self.pokemon.stats.base_stamina + self.sta_iv.value()) as f64;
     a * d * s
   }
 
@@ -165,19 +462,19 @@ impl PokemonInstance {
   }
 
   pub fn type_effectiveness<M: Damage>(&self, move_: &M) -> f64 {
-    *self.pokemon.type_effectiveness.get(move_.type_()).unwrap() // UNWRAP SAFE: key should always be defined
+    self.pokemon.type_effectiveness[*move_.type_()]
   }
 
   pub fn new(
-    pokemon: Pokemon,
+    pokemon: Arc<Pokemon>,
     level: Level,
     cpm: f64,
-    atk_iv: u16,
-    def_iv: u16,
-    sta_iv: u16,
-    fast_move: FastMove,
-    charged_move1: ChargedMove,
-    charged_move2: ChargedMove,
+    atk_iv: Iv,
+    def_iv: Iv,
+    sta_iv: Iv,
+    fast_move: Arc<FastMove>,
+    charged_move1: Arc<ChargedMove>,
+    charged_move2: Arc<ChargedMove>,
   ) -> PokemonInstance {
     PokemonInstance {
       pokemon,
@@ -186,9 +483,37 @@ impl PokemonInstance {
       atk_iv,
       def_iv,
       sta_iv,
+      variant: Variant::Normal,
+      attack_multiplier: 1.,
+      defense_multiplier: 1.,
       fast_move,
       charged_move1,
       charged_move2,
     }
   }
+
+  // Shadow Pokémon hit harder and take more damage; CP, HP and stat product
+  // are unaffected
+  pub fn with_variant(self, variant: Variant, settings: &CombatSettings) -> PokemonInstance {
+    let (attack_multiplier, defense_multiplier) = match variant {
+      Variant::Shadow => (settings.shadow_attack_bonus, settings.shadow_defense_bonus),
+      Variant::Normal | Variant::Purified => (1., 1.),
+    };
+    PokemonInstance {
+      variant,
+      attack_multiplier,
+      defense_multiplier,
+      ..self
+    }
+  }
+
+  // Attack and defense as changed by stat stages in battle, on top of any
+  // shadow bonus
+  pub fn with_stat_stages(self, attack_multiplier: f64, defense_multiplier: f64) -> PokemonInstance {
+    PokemonInstance {
+      attack_multiplier: self.attack_multiplier * attack_multiplier,
+      defense_multiplier: self.defense_multiplier * defense_multiplier,
+      ..self
+    }
+  }
 }
@@ -0,0 +1,355 @@
+use crate::error::*;
+use crate::gamemaster as gm;
+use crate::model::pokemon::{Level, Variant};
+
+use std::convert::TryFrom;
+use std::ops::Add;
+
+// ======================
+// === CombatSettings ===
+// ======================
+
+// Constants from the COMBAT_SETTINGS template. Multipliers missing from older
+// game masters default to 1 (no effect); durations are in seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CombatSettings {
+  pub same_type_attack_bonus: f64,
+  pub fast_attack_bonus: f64,
+  pub charged_attack_bonus: f64,
+  pub defense_bonus: f64,
+  pub max_energy: i16,
+  pub turn_duration: f64,
+  pub round_duration: Option<f64>,
+  pub switch_duration: Option<f64>,
+  pub switch_cooldown: Option<f64>,
+  pub shadow_attack_bonus: f64,
+  pub shadow_defense_bonus: f64,
+  pub purified_attack_vs_shadow: f64,
+}
+
+impl TryFrom<&gm::CombatSettings> for CombatSettings {
+  type Error = Error;
+
+  fn try_from(s: &gm::CombatSettings) -> Result<Self, Self::Error> {
+    Ok(CombatSettings {
+      same_type_attack_bonus: s.same_type_attack_bonus_multiplier,
+      fast_attack_bonus: s.fast_attack_bonus_multiplier,
+      charged_attack_bonus: s.charge_attack_bonus_multiplier,
+      defense_bonus: s.defense_bonus_multiplier.unwrap_or(1.),
+      max_energy: i16::try_from(s.max_energy).map_err(|e| {
+        Error::ConversionError(format!("Can't convert max energy {}: {:?}", s.max_energy, e))
+      })?,
+      turn_duration: s.turn_duration_seconds.unwrap_or(0.5),
+      round_duration: s.round_duration_seconds,
+      switch_duration: s.change_pokemon_duration_seconds,
+      switch_cooldown: s.quick_swap_cooldown_duration_seconds,
+      shadow_attack_bonus: s.shadow_pokemon_attack_bonus_multiplier.unwrap_or(1.),
+      shadow_defense_bonus: s.shadow_pokemon_defense_bonus_multiplier.unwrap_or(1.),
+      purified_attack_vs_shadow: s.purified_pokemon_attack_multiplier_vs_shadow.unwrap_or(1.),
+    })
+  }
+}
+
+impl CombatSettings {
+  // Whole turns in `seconds`
+  pub fn turns(&self, seconds: f64) -> u16 {
+    (seconds / self.turn_duration).round() as u16
+  }
+
+  // Turns in a battle, 4 minutes if the game master doesn't say
+  pub fn round_turns(&self) -> u16 {
+    self.turns(self.round_duration.unwrap_or(240.))
+  }
+
+  // Turns before a switched out Pokémon's side can switch again, 60 seconds
+  // if the game master doesn't say
+  pub fn switch_cooldown_turns(&self) -> u16 {
+    self.turns(self.switch_cooldown.unwrap_or(60.))
+  }
+}
+
+// =======================
+// === UpgradeSettings ===
+// =======================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PowerUpCost {
+  pub stardust: u64,
+  pub candy: u64,
+  pub xl_candy: u64,
+}
+
+impl Add for PowerUpCost {
+  type Output = PowerUpCost;
+
+  fn add(self, rhs: PowerUpCost) -> PowerUpCost {
+    PowerUpCost {
+      stardust: self.stardust + rhs.stardust,
+      candy: self.candy + rhs.candy,
+      xl_candy: self.xl_candy + rhs.xl_candy,
+    }
+  }
+}
+
+// Power-up costs from the POKEMON_UPGRADE_SETTINGS template, indexed by
+// integer level (i.e. [0] is the cost of powering up from level 1 or 1.5).
+// From max_normal_upgrade_level on, power-ups take XL candy instead of candy.
+#[derive(Debug, Clone)]
+pub struct UpgradeSettings {
+  pub candy_cost: Vec<u16>,
+  pub stardust_cost: Vec<u16>,
+  pub xl_candy_cost: Vec<u16>,
+  pub max_normal_upgrade_level: Option<u16>,
+  pub shadow_stardust_multiplier: f64,
+  pub shadow_candy_multiplier: f64,
+  pub purified_stardust_multiplier: f64,
+  pub purified_candy_multiplier: f64,
+}
+
+impl From<&gm::PokemonUpgrades> for UpgradeSettings {
+  fn from(u: &gm::PokemonUpgrades) -> Self {
+    UpgradeSettings {
+      candy_cost: u.candy_cost.clone(),
+      stardust_cost: u.stardust_cost.clone(),
+      xl_candy_cost: u.xl_candy_cost.clone(),
+      max_normal_upgrade_level: u.max_normal_upgrade_level,
+      shadow_stardust_multiplier: u.shadow_stardust_multiplier,
+      shadow_candy_multiplier: u.shadow_candy_multiplier,
+      purified_stardust_multiplier: u.purified_stardust_multiplier,
+      purified_candy_multiplier: u.purified_candy_multiplier,
+    }
+  }
+}
+
+impl UpgradeSettings {
+  pub fn stardust_multiplier(&self, variant: Variant) -> f64 {
+    match variant {
+      Variant::Normal => 1.,
+      Variant::Shadow => self.shadow_stardust_multiplier,
+      Variant::Purified => self.purified_stardust_multiplier,
+    }
+  }
+
+  pub fn candy_multiplier(&self, variant: Variant) -> f64 {
+    match variant {
+      Variant::Normal => 1.,
+      Variant::Shadow => self.shadow_candy_multiplier,
+      Variant::Purified => self.purified_candy_multiplier,
+    }
+  }
+
+  // Total cost of powering up from `from` to `to`, one half level at a time.
+  // Variant multipliers round up per power-up, as in game; lucky Pokémon pay
+  // half the stardust.
+  pub fn power_up_cost(
+    &self,
+    from: Level,
+    to: Level,
+    variant: Variant,
+    lucky: bool,
+  ) -> Result<PowerUpCost, Error> {
+    let stardust_multiplier = self.stardust_multiplier(variant) * if lucky { 0.5 } else { 1. };
+    let candy_multiplier = self.candy_multiplier(variant);
+
+    let mut total = PowerUpCost::default();
+    let mut level = from;
+    while level < to {
+      let idx = (level.whole() - 1) as usize;
+      let out_of_bounds = || {
+        Error::BoundsError(format!("No power-up cost for level {:?}", level))
+      };
+
+      let stardust = *self.stardust_cost.get(idx).ok_or_else(out_of_bounds)?;
+      total.stardust += scale_cost(stardust.into(), stardust_multiplier);
+
+      match self.max_normal_upgrade_level {
+        Some(max) if level.whole() >= max => {
+          let xl_idx = (level.whole() - max) as usize;
+          let xl_candy = *self.xl_candy_cost.get(xl_idx).ok_or_else(out_of_bounds)?;
+          total.xl_candy += scale_cost(xl_candy.into(), candy_multiplier);
+        }
+        _ => {
+          let candy = *self.candy_cost.get(idx).ok_or_else(out_of_bounds)?;
+          total.candy += scale_cost(candy.into(), candy_multiplier);
+        }
+      }
+
+      level = level.next();
+    }
+
+    Ok(total)
+  }
+
+  // Unlocking the second charged move is subject to the variant multipliers
+  // too, but not to the lucky discount
+  pub fn unlock_cost(&self, base: &PowerUpCost, variant: Variant) -> PowerUpCost {
+    let stardust_multiplier = self.stardust_multiplier(variant);
+    let candy_multiplier = self.candy_multiplier(variant);
+    PowerUpCost {
+      stardust: scale_cost(base.stardust, stardust_multiplier),
+      candy: scale_cost(base.candy, candy_multiplier),
+      xl_candy: scale_cost(base.xl_candy, candy_multiplier),
+    }
+  }
+}
+
+// Round up, but don't let float noise (e.g. 200 * 1.2 = 240.00000000000003)
+// cost an extra unit
+fn scale_cost(cost: u64, multiplier: f64) -> u64 {
+  (cost as f64 * multiplier - 1e-6).ceil() as u64
+}
+
+// =========================
+// === StatStageSettings ===
+// =========================
+
+// Attack/defense multipliers for each stat stage, from minimum_stage to
+// maximum_stage (i.e. -4..=4, where stage 0 is 1x)
+#[derive(Debug, Clone)]
+pub struct StatStageSettings {
+  pub minimum_stage: i8,
+  pub maximum_stage: i8,
+  attack_multipliers: Vec<f64>,
+  defense_multipliers: Vec<f64>,
+}
+
+impl TryFrom<&gm::CombatStatStageSettings> for StatStageSettings {
+  type Error = Error;
+
+  fn try_from(s: &gm::CombatStatStageSettings) -> Result<Self, Self::Error> {
+    let stage = |s: i32| {
+      i8::try_from(s).map_err(|e| {
+        Error::ConversionError(format!("Can't convert stat stage {}: {:?}", s, e))
+      })
+    };
+    let minimum_stage = stage(s.minimum_stat_stage)?;
+    let maximum_stage = stage(s.maximum_stat_stage)?;
+
+    if minimum_stage > 0 || maximum_stage < 0 {
+      return Err(Error::ConversionError(format!(
+        "Stat stage range {}..={} doesn't include 0",
+        minimum_stage, maximum_stage
+      )));
+    }
+
+    let stages = (maximum_stage - minimum_stage) as usize + 1;
+    if s.attack_buff_multiplier.len() != stages || s.defense_buff_multiplier.len() != stages {
+      return Err(Error::ConversionError(format!(
+        "{}/{} attack/defense stat stage multipliers != {} stages",
+        s.attack_buff_multiplier.len(),
+        s.defense_buff_multiplier.len(),
+        stages
+      )));
+    }
+
+    Ok(StatStageSettings {
+      minimum_stage,
+      maximum_stage,
+      attack_multipliers: s.attack_buff_multiplier.clone(),
+      defense_multipliers: s.defense_buff_multiplier.clone(),
+    })
+  }
+}
+
+impl StatStageSettings {
+  pub fn clamp(&self, stage: i8) -> i8 {
+    stage.clamp(self.minimum_stage, self.maximum_stage)
+  }
+
+  pub fn attack_multiplier(&self, stage: i8) -> f64 {
+    self.attack_multipliers[(self.clamp(stage) - self.minimum_stage) as usize]
+  }
+
+  pub fn defense_multiplier(&self, stage: i8) -> f64 {
+    self.defense_multipliers[(self.clamp(stage) - self.minimum_stage) as usize]
+  }
+
+  // Fractional stages, e.g. the expected stage after a buff with a 50%
+  // chance, fall linearly between the two whole stages around them
+  pub fn attack_multiplier_at(&self, stage: f64) -> f64 {
+    self.interpolate(&self.attack_multipliers, stage)
+  }
+
+  pub fn defense_multiplier_at(&self, stage: f64) -> f64 {
+    self.interpolate(&self.defense_multipliers, stage)
+  }
+
+  fn interpolate(&self, multipliers: &[f64], stage: f64) -> f64 {
+    let stage = stage.clamp(self.minimum_stage as f64, self.maximum_stage as f64);
+    let index = stage - self.minimum_stage as f64;
+    let (low, high) = (index.floor() as usize, index.ceil() as usize);
+    multipliers[low] + (multipliers[high] - multipliers[low]) * index.fract()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_stat_stages() {
+    let gm_settings = gm::CombatStatStageSettings {
+      minimum_stat_stage: -4,
+      maximum_stat_stage: 4,
+      attack_buff_multiplier: vec![0.5, 0.5714286, 0.6666667, 0.8, 1.0, 1.25, 1.5, 1.75, 2.0],
+      defense_buff_multiplier: vec![0.5, 0.5714286, 0.6666667, 0.8, 1.0, 1.25, 1.5, 1.75, 2.0],
+    };
+    let stages = StatStageSettings::try_from(&gm_settings).unwrap();
+
+    assert_eq!(stages.attack_multiplier(0), 1.0);
+    assert_eq!(stages.attack_multiplier(1), 1.25);
+    assert_eq!(stages.defense_multiplier(-1), 0.8);
+    assert_eq!(stages.attack_multiplier(4), 2.0);
+    assert_eq!(stages.attack_multiplier(7), 2.0);
+    assert_eq!(stages.defense_multiplier(-9), 0.5);
+    assert_eq!(stages.attack_multiplier_at(1.), 1.25);
+    assert_eq!(stages.attack_multiplier_at(0.5), 1.125);
+    assert_eq!(stages.defense_multiplier_at(-0.5), 0.9);
+    assert_eq!(stages.attack_multiplier_at(9.5), 2.0);
+
+    let truncated = gm::CombatStatStageSettings {
+      attack_buff_multiplier: vec![1.0],
+      ..gm_settings
+    };
+    assert!(StatStageSettings::try_from(&truncated).is_err());
+  }
+
+  #[test]
+  fn test_power_up_cost() {
+    let upgrades = UpgradeSettings {
+      candy_cost: vec![1, 2],
+      stardust_cost: vec![100, 200, 300],
+      xl_candy_cost: vec![10],
+      max_normal_upgrade_level: Some(2),
+      shadow_stardust_multiplier: 1.2,
+      shadow_candy_multiplier: 1.2,
+      purified_stardust_multiplier: 0.9,
+      purified_candy_multiplier: 0.9,
+    };
+    let level = |level, a_half| Level::new(level, a_half).unwrap();
+
+    assert_eq!(
+      upgrades.power_up_cost(level(1, false), level(3, false), Variant::Normal, false).unwrap(),
+      PowerUpCost { stardust: 600, candy: 2, xl_candy: 20 }
+    );
+    assert_eq!(
+      upgrades.power_up_cost(level(1, true), level(2, false), Variant::Shadow, false).unwrap(),
+      PowerUpCost { stardust: 120, candy: 2, xl_candy: 0 }
+    );
+    assert_eq!(
+      upgrades.power_up_cost(level(1, false), level(2, false), Variant::Purified, true).unwrap(),
+      PowerUpCost { stardust: 90, candy: 2, xl_candy: 0 }
+    );
+    assert_eq!(
+      upgrades.power_up_cost(level(2, false), level(1, false), Variant::Normal, false).unwrap(),
+      PowerUpCost::default()
+    );
+    assert!(upgrades.power_up_cost(level(1, false), level(3, true), Variant::Normal, false).is_err());
+
+    let unlock = PowerUpCost { stardust: 10000, candy: 25, xl_candy: 0 };
+    assert_eq!(
+      upgrades.unlock_cost(&unlock, Variant::Shadow),
+      PowerUpCost { stardust: 12000, candy: 30, xl_candy: 0 }
+    );
+  }
+}
@@ -0,0 +1,218 @@
+use crate::error::*;
+use crate::gamemaster as gm;
+use crate::model::{Type, TYPE_ORDERING};
+
+use std::convert::TryFrom;
+use std::ops::Index;
+
+const TYPE_COUNT: usize = 18;
+
+// =================
+// === TypeChart ===
+// =================
+
+// Damage multipliers of every attacking type against every defending type.
+// Rows are attacking types and columns defending types, both in TYPE_ORDERING,
+// which is also the order of `attackScalar` in the GameMaster.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TypeChart {
+  matrix: [[f64; TYPE_COUNT]; TYPE_COUNT],
+}
+
+impl TypeChart {
+  pub fn attack(&self, attacking: Type, defending: Type) -> f64 {
+    self.matrix[attacking as usize][defending as usize]
+  }
+
+  // What each attacking type does to a Pokémon of `defender` types (one or
+  // two), e.g. 0.390625 for a double resistance and 2.56 for a double weakness
+  pub fn defend(&self, defender: &[Type]) -> TypeMultipliers {
+    let mut multipliers = [1.; TYPE_COUNT];
+    for (attacking, m) in multipliers.iter_mut().enumerate() {
+      for defending in defender {
+        *m *= self.matrix[attacking][*defending as usize];
+      }
+    }
+    TypeMultipliers(multipliers)
+  }
+}
+
+impl TryFrom<&[&gm::TypeEffectiveness]> for TypeChart {
+  type Error = Error;
+
+  fn try_from(entries: &[&gm::TypeEffectiveness]) -> Result<Self, Self::Error> {
+    let mut rows: [Option<[f64; TYPE_COUNT]>; TYPE_COUNT] = [None; TYPE_COUNT];
+
+    for t in entries {
+      let attacking = Type::try_from(t.attack_type.as_str())?;
+      if t.effectiveness.len() < TYPE_COUNT {
+        return Err(Error::ParseError(format!(
+          "{} has {} < {} effectiveness entries",
+          t.attack_type,
+          t.effectiveness.len(),
+          TYPE_COUNT
+        )));
+      }
+      if rows[attacking as usize].is_some() {
+        return Err(Error::ParseError(format!(
+          "Duplicate TypeEffectiveness entry for {}",
+          t.attack_type
+        )));
+      }
+
+      let mut row = [0.; TYPE_COUNT];
+      row.copy_from_slice(&t.effectiveness[..TYPE_COUNT]);
+      rows[attacking as usize] = Some(row);
+    }
+
+    let mut matrix = [[0.; TYPE_COUNT]; TYPE_COUNT];
+    for (t, row) in TYPE_ORDERING.iter().zip(rows.iter()) {
+      matrix[*t as usize] = row.ok_or_else(|| {
+        Error::ParseError(format!("Missing TypeEffectiveness entry for {:?}", t))
+      })?;
+    }
+
+    Ok(TypeChart { matrix })
+  }
+}
+
+// =======================
+// === TypeMultipliers ===
+// =======================
+
+// Damage multiplier for each attacking type against a given defender
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TypeMultipliers([f64; TYPE_COUNT]);
+
+impl TypeMultipliers {
+  pub fn iter(&self) -> impl Iterator<Item = (Type, f64)> + '_ {
+    TYPE_ORDERING.iter().map(move |t| (*t, self[*t]))
+  }
+}
+
+impl Index<Type> for TypeMultipliers {
+  type Output = f64;
+
+  fn index(&self, attacking: Type) -> &f64 {
+    &self.0[attacking as usize]
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::gamemaster::test_fixture;
+  use crate::model::Mechanics;
+
+  // (attacking, super effective against, not very effective against, no effect
+  // against), where "no effect" is a double resistance in Pokémon GO
+  type ChartRow = (Type, Vec<Type>, Vec<Type>, Vec<Type>);
+
+  // The official chart
+  fn official_chart() -> Vec<ChartRow> {
+    use Type::*;
+    vec![
+      (Normal, vec![], vec![Rock, Steel], vec![Ghost]),
+      (Fighting, vec![Normal, Rock, Steel, Ice, Dark], vec![Flying, Poison, Bug, Psychic, Fairy], vec![Ghost]),
+      (Flying, vec![Fighting, Bug, Grass], vec![Rock, Steel, Electric], vec![]),
+      (Poison, vec![Grass, Fairy], vec![Poison, Ground, Rock, Ghost], vec![Steel]),
+      (Ground, vec![Poison, Rock, Steel, Fire, Electric], vec![Bug, Grass], vec![Flying]),
+      (Rock, vec![Flying, Bug, Fire, Ice], vec![Fighting, Ground, Steel], vec![]),
+      (Bug, vec![Grass, Psychic, Dark], vec![Fighting, Flying, Poison, Ghost, Steel, Fire, Fairy], vec![]),
+      (Ghost, vec![Ghost, Psychic], vec![Dark], vec![Normal]),
+      (Steel, vec![Rock, Ice, Fairy], vec![Steel, Fire, Water, Electric], vec![]),
+      (Fire, vec![Bug, Steel, Grass, Ice], vec![Rock, Fire, Water, Dragon], vec![]),
+      (Water, vec![Ground, Rock, Fire], vec![Water, Grass, Dragon], vec![]),
+      (Grass, vec![Ground, Rock, Water], vec![Flying, Poison, Bug, Steel, Fire, Grass, Dragon], vec![]),
+      (Electric, vec![Flying, Water], vec![Grass, Electric, Dragon], vec![Ground]),
+      (Psychic, vec![Fighting, Poison], vec![Steel, Psychic], vec![Dark]),
+      (Ice, vec![Flying, Ground, Grass, Dragon], vec![Steel, Fire, Water, Ice], vec![]),
+      (Dragon, vec![Dragon], vec![Steel], vec![Fairy]),
+      (Dark, vec![Ghost, Psychic], vec![Fighting, Dark, Fairy], vec![]),
+      (Fairy, vec![Fighting, Dragon, Dark], vec![Poison, Steel, Fire], vec![]),
+    ]
+  }
+
+  fn close(a: f64, b: f64) -> bool {
+    (a - b).abs() < 1e-6
+  }
+
+  #[test]
+  fn test_official_chart() {
+    let chart = Mechanics::from_gamemaster(&test_fixture()).unwrap().type_effectiveness;
+
+    let official = official_chart();
+    assert_eq!(official.len(), TYPE_ORDERING.len());
+    for (attacking, strong, weak, immune) in &official {
+      for defending in TYPE_ORDERING {
+        let expected = if strong.contains(defending) {
+          1.6
+        } else if weak.contains(defending) {
+          0.625
+        } else if immune.contains(defending) {
+          0.390625
+        } else {
+          1.
+        };
+        assert!(
+          close(chart.attack(*attacking, *defending), expected),
+          "{:?} vs {:?}: {} != {}",
+          attacking,
+          defending,
+          chart.attack(*attacking, *defending),
+          expected
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn test_defend() {
+    use Type::*;
+    let chart = Mechanics::from_gamemaster(&test_fixture()).unwrap().type_effectiveness;
+
+    // Not symmetric: fighting beats normal, normal doesn't touch fighting
+    assert!(close(chart.attack(Fighting, Normal), 1.6));
+    assert!(close(chart.attack(Normal, Fighting), 1.));
+
+    let steel_psychic = chart.defend(&[Steel, Psychic]);
+    assert!(close(steel_psychic[Poison], 0.390625));
+    assert!(close(steel_psychic[Psychic], 0.390625));
+    assert!(close(steel_psychic[Ghost], 1.6));
+    assert!(close(steel_psychic[Fire], 1.6));
+
+    let grass_ice = chart.defend(&[Grass, Ice]);
+    assert!(close(grass_ice[Fire], 2.56));
+    assert!(close(chart.defend(&[Water, Dragon])[Fire], 0.390625));
+    assert!(close(chart.defend(&[Ground, Flying])[Electric], 0.390625 * 1.6));
+    assert!(close(chart.defend(&[Fairy])[Dragon], 0.390625));
+    assert_eq!(chart.defend(&[]).iter().filter(|(_, m)| *m == 1.).count(), 18);
+  }
+
+  #[test]
+  fn test_chart_errors() {
+    let entry = |t: &str| gm::TypeEffectiveness {
+      attack_type: t.to_owned(),
+      effectiveness: vec![1.; TYPE_COUNT],
+    };
+    let all: Vec<_> = TYPE_ORDERING
+      .iter()
+      .map(|t| entry(&format!("POKEMON_TYPE_{:?}", t).to_uppercase()))
+      .collect();
+    let refs: Vec<_> = all.iter().collect();
+    assert!(TypeChart::try_from(refs.as_slice()).is_ok());
+    assert!(TypeChart::try_from(&refs[1..]).is_err());
+
+    let mut duplicate = refs.clone();
+    duplicate[1] = refs[0];
+    assert!(TypeChart::try_from(duplicate.as_slice()).is_err());
+
+    let short = gm::TypeEffectiveness {
+      attack_type: "POKEMON_TYPE_NORMAL".to_owned(),
+      effectiveness: vec![1.; 17],
+    };
+    let mut with_short = refs.clone();
+    with_short[0] = &short;
+    assert!(TypeChart::try_from(with_short.as_slice()).is_err());
+  }
+}
@@ -1,86 +1,184 @@
-use crate::model::{Level, Mechanics, PokemonInstance};
+use crate::error::Error;
+use crate::model::{Iv, League, Level, Mechanics, SpeciesId};
 
-use std::collections::BTreeMap;
+// =================
+// === Encounter ===
+// =================
 
-fn max_statproduct(mech: &Mechanics, pokemon_id: &str, cap: usize) -> Result<(i32, i32, i32, Level, u32), ()> {
-  let pok = mech.pokemon(pokemon_id).ok_or_else(|| ())?;
-  const MIN_LEVEL: Level = Level { level: 1, a_half: false };
+// How a Pokémon was obtained: the lowest IV it can have in each stat, and the
+// level range it can end up at (it can be powered up, but never down)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Encounter {
+  pub iv_floor: Iv,
+  pub min_level: Level,
+  pub max_level: Option<Level>,
+}
 
-  let (base_atk, base_def, base_sta): (f64, f64, f64) = 
-    (pok.stats.base_attack as _, pok.stats.base_defense as _, pok.stats.base_stamina as _);
+impl Encounter {
+  pub const WILD: Encounter = Encounter::new(Iv::new_unchecked(0), Level::MIN);
+  pub const WEATHER_BOOSTED: Encounter = Encounter::new(Iv::new_unchecked(4), Level::new_unchecked(6, false));
+  pub const RESEARCH: Encounter = Encounter::new(Iv::new_unchecked(10), Level::new_unchecked(15, false));
+  pub const RAID: Encounter = Encounter::new(Iv::new_unchecked(10), Level::new_unchecked(20, false));
+  pub const RAID_BOOSTED: Encounter = Encounter::new(Iv::new_unchecked(10), Level::new_unchecked(25, false));
+  // Trades keep the level, so any level goes
+  pub const GOOD_FRIEND_TRADE: Encounter = Encounter::new(Iv::new_unchecked(1), Level::MIN);
+  pub const GREAT_FRIEND_TRADE: Encounter = Encounter::new(Iv::new_unchecked(2), Level::MIN);
+  pub const ULTRA_FRIEND_TRADE: Encounter = Encounter::new(Iv::new_unchecked(3), Level::MIN);
+  pub const BEST_FRIEND_TRADE: Encounter = Encounter::new(Iv::new_unchecked(5), Level::MIN);
+  pub const LUCKY_TRADE: Encounter = Encounter::new(Iv::new_unchecked(12), Level::MIN);
+
+  pub const fn new(iv_floor: Iv, min_level: Level) -> Encounter {
+    Encounter {
+      iv_floor,
+      min_level,
+      max_level: None,
+    }
+  }
 
-  let (max_atk, max_def, max_sta) =
-    (base_atk + 15., base_def + 15., base_sta + 15.)
-    as (f64, f64, f64);
+  pub fn with_max_level(self, max_level: Level) -> Encounter {
+    Encounter { max_level: Some(max_level), ..self }
+  }
+}
 
-  let max_cpm = (10f64 * cap as f64 / (base_atk * base_def.sqrt() * base_sta.floor().sqrt())).sqrt();
-  let min_cpm = (10f64 * cap as f64 / (max_atk * max_def.sqrt() * max_sta.floor().sqrt())).sqrt();
+// =================
+// === RankEntry ===
+// =================
 
-  let cpms: Vec<(f64, Level)> = (0..79)
-    .map(|i| Level::from(i))
-    .map(|i| {
-      (mech.cp_multiplier(&i), i)
-    })
-    .collect();
+// Best league-legal build of one IV spread. `rank` is among the spreads the
+// encounter allows, `overall_rank` among all 4096 spreads of a wild catch.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RankEntry {
+  pub atk: Iv,
+  pub def: Iv,
+  pub sta: Iv,
+  pub level: Level,
+  pub cp: u32,
+  pub stat_product: f64,
+  pub rank: usize,
+  pub overall_rank: usize,
+}
+
+pub fn max_statproduct<S: Into<SpeciesId>>(
+  mech: &Mechanics,
+  species: S,
+  league: &League,
+  encounter: &Encounter,
+) -> Result<(Iv, Iv, Iv, Level, u32), Error> {
+  let best = rank_table(mech, species, league, encounter)?[0];
+  Ok((best.atk, best.def, best.sta, best.level, best.stat_product.round() as u32))
+}
+
+// Every spread the encounter allows, best stat product first
+pub fn rank_table<S: Into<SpeciesId>>(
+  mech: &Mechanics,
+  species: S,
+  league: &League,
+  encounter: &Encounter,
+) -> Result<Vec<RankEntry>, Error> {
+  let species = species.into();
+  let pok = mech
+    .pokemon(&species)
+    .ok_or_else(|| Error::BoundsError(format!("Could not find pokemon {}", species)))?;
+  if !league.allows(&pok) {
+    return Err(Error::BoundsError(format!("{} is not allowed in {}", species, league.id)));
+  }
+  // Uncapped leagues just pick the highest level
+  let cap = league.cp_cap.map_or(f64::INFINITY, |c| c as f64);
+
+  let (base_atk, base_def, base_sta): (f64, f64, f64) =
+    (pok.stats.base_attack as _, pok.stats.base_defense as _, pok.stats.base_stamina as _);
 
-  let min_level = cpms.iter()
-    .find(|(cpm, _)| cpm >= &min_cpm)
-    .map(|&(_, level)| level)
-    .unwrap_or_else(|| Level { level: 1, a_half: false });
-  let max_level = cpms.iter()
-    .rfind(|(cpm, _)| cpm <= &max_cpm)
-    .map(|&(_, level)| level)
-    .unwrap_or_else(|| Level { level: 40, a_half: false });
+  // Past this CPM even a 0/0/0 spread goes over the cap
+  let max_cpm = (10f64 * cap / (base_atk * base_def.sqrt() * base_sta.floor().sqrt())).sqrt();
 
-  let instance = iv_combinations.iter()
-    .map(|&(atk, def, sta)| {
-      let mut level = max_level;
+  let level_cap = [Some(mech.level_cap), league.level_cap, encounter.max_level]
+    .iter()
+    .flatten()
+    .min()
+    .copied()
+    .unwrap_or(mech.level_cap);
+  let max_level = mech
+    .levels()
+    .filter(|(l, cpm)| l <= &level_cap && cpm <= &max_cpm)
+    .map(|(l, _)| l)
+    .last()
+    .unwrap_or(Level::MIN);
 
-      while level >= MIN_LEVEL {
+  // Highest level from `min_level` up that stays under the cap
+  let best_build = |atk: Iv, def: Iv, sta: Iv, min_level: Level| {
+    let a = (pok.stats.base_attack + atk.value()) as f64;
+    let d = (pok.stats.base_defense + def.value()) as f64;
+    let s = (pok.stats.base_stamina + sta.value()) as f64;
 
-        let cpm = mech.cp_multiplier(&level);
-        let a = (pok.stats.base_attack + atk as u16) as f64;
-        let d = (pok.stats.base_defense + def as u16) as f64;
-        let s = (pok.stats.base_stamina + sta as u16) as f64;
+    let mut level = max_level;
+    while level >= min_level {
+      let cpm = mech.cp_multiplier(&level).ok()?;
+      let cp = f64::floor(a * d.sqrt() * s.floor().sqrt() * cpm * cpm / 10.) as u32;
 
-        // There's a waste of computation around here
-        let cp = f64::floor(a * d.sqrt() * s.floor().sqrt() * cpm * cpm / 10.) as u32;
+      if cp as f64 <= cap {
         let stat_product = a * cpm * d * cpm * (s * cpm).floor() / 1000.;
-
-        if cp <= cap as _ {
-          return (atk, def, sta, level, stat_product)
-        }
-        level = level.prev();
+        return Some((level, cp, stat_product));
       }
+      if level == Level::MIN {
+        break;
+      }
+      level = level.prev();
+    }
+    None
+  };
+
+  let mut overall: Vec<f64> = iv_combinations
+    .iter()
+    .filter_map(|&(atk, def, sta)| best_build(atk, def, sta, Level::MIN))
+    .map(|(_, _, stat_product)| stat_product)
+    .collect();
+  overall.sort_by(|a, b| b.partial_cmp(a).unwrap());
 
-      (atk, def, sta, level, 0.) // should be unreachable
+  let mut table: Vec<RankEntry> = iv_combinations
+    .iter()
+    .filter(|&&(atk, def, sta)| std::cmp::min(atk, std::cmp::min(def, sta)) >= encounter.iv_floor)
+    .filter_map(|&(atk, def, sta)| {
+      best_build(atk, def, sta, encounter.min_level).map(|(level, cp, stat_product)| RankEntry {
+        atk,
+        def,
+        sta,
+        level,
+        cp,
+        stat_product,
+        rank: 0,
+        overall_rank: overall.partition_point(|&sp| sp > stat_product) + 1,
+      })
     })
-    .fold((0, 0, 0, MIN_LEVEL, 0.), |max, cur| {
-      if max.4 <= cur.4 {
-        cur
-      } else {
-        max
-      }
-    });
+    .collect();
+  if table.is_empty() {
+    return Err(Error::BoundsError(format!(
+      "No spread of {} from {:?} fits in {}",
+      species, encounter, league.id
+    )));
+  }
 
-  println!("{:?}", instance);
+  // Ties go to the higher IVs
+  table.sort_by(|a, b| {
+    b.stat_product
+      .partial_cmp(&a.stat_product)
+      .unwrap()
+      .then((b.atk, b.def, b.sta).cmp(&(a.atk, a.def, a.sta)))
+  });
+  let products: Vec<f64> = table.iter().map(|e| e.stat_product).collect();
+  for e in table.iter_mut() {
+    e.rank = products.partition_point(|&sp| sp > e.stat_product) + 1;
+  }
 
-  Ok((
-      instance.0,
-      instance.1,
-      instance.2,
-      instance.3,
-      instance.4.round() as u32
-    ))
+  Ok(table)
 }
 
 lazy_static! {
-  static ref iv_combinations: Vec<(i32, i32, i32)> = {
-    (0..=15).into_iter()
-      .flat_map(move |i: i32|
-        (0..=15).into_iter()
-          .flat_map(move |j: i32|
-            (0..=15).into_iter()
+  static ref iv_combinations: Vec<(Iv, Iv, Iv)> = {
+    Iv::all()
+      .flat_map(move |i|
+        Iv::all()
+          .flat_map(move |j|
+            Iv::all()
               .map(move |k| (i, j, k))))
               .collect()
   };
@@ -89,9 +187,12 @@ lazy_static! {
 #[cfg(test)]
 mod tests {
   use super::*;
-  use crate::gamemaster::GameMaster;
+  use crate::gamemaster::{test_fixture, GameMaster};
+  use crate::model::PokemonInstance;
 
-  use std::convert::TryFrom;
+  fn iv(v: u16) -> Iv {
+    Iv::new(v).unwrap()
+  }
 
   #[test]
   fn test_iv_combs() {
@@ -101,39 +202,145 @@ mod tests {
   #[test]
   fn test_max_statproduct() {
     let mech = Mechanics::instance();
+    let great = League::new("great", Some(1500));
 
     use std::time::Instant;
 
     let start = Instant::now();
     assert_eq!(
-      max_statproduct(&mech, "ALTARIA", 1500).unwrap(),
-      (0, 14, 15, Level { level: 29, a_half: false }, 2212)
+      max_statproduct(&mech, "ALTARIA", &great, &Encounter::WILD).unwrap(),
+      (iv(0), iv(14), iv(15), Level::new(29, false).unwrap(), 2212)
+    );
+    let dur = Instant::now() - start;
+    println!("{:?}", dur);
+
+    // Without XL candy, Wobbuffet tops out at level 40
+    let no_xl = Mechanics::from_gamemaster(GameMaster::instance())
+      .unwrap()
+      .with_level_cap(Level::new(40, false).unwrap())
+      .unwrap();
+    let start = Instant::now();
+    assert_eq!(
+      max_statproduct(&no_xl, "WOBBUFFET", &great, &Encounter::WILD).unwrap(),
+      (iv(15), iv(15), iv(15), Level::new(40, false).unwrap(), 1774)
     );
     let dur = Instant::now() - start;
     println!("{:?}", dur);
 
     let start = Instant::now();
     assert_eq!(
-      max_statproduct(&mech, "WOBBUFFET", 1500).unwrap(),
-      (15, 15, 15, Level { level: 40, a_half: false }, 1774)
+      max_statproduct(&mech, "WOBBUFFET", &great, &Encounter::WILD).unwrap(),
+      (iv(15), iv(15), iv(15), Level::new(51, false).unwrap(), 2172)
     );
     let dur = Instant::now() - start;
     println!("{:?}", dur);
 
     let start = Instant::now();
     assert_eq!(
-      max_statproduct(&mech, "BLISSEY", 1500).unwrap(),
-      (0, 15, 3, Level { level: 21, a_half: true }, 2814)
+      max_statproduct(&mech, "BLISSEY", &great, &Encounter::WILD).unwrap(),
+      (iv(0), iv(15), iv(3), Level::new(21, true).unwrap(), 2814)
     );
     let dur = Instant::now() - start;
     println!("{:?}", dur);
 
     let start = Instant::now();
     assert_eq!(
-      max_statproduct(&mech, "GENGAR", 1500).unwrap(),
-      (0, 13, 13, Level { level: 19, a_half: true }, 1457)
+      max_statproduct(&mech, "GENGAR", &great, &Encounter::WILD).unwrap(),
+      (iv(0), iv(13), iv(13), Level::new(19, true).unwrap(), 1457)
     );
     let dur = Instant::now() - start;
     println!("{:?}", dur);
   }
+
+  #[test]
+  fn test_max_statproduct_league_rules() {
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
+
+    let master = League::new("master", None);
+    assert_eq!(
+      max_statproduct(&mech, "ALTARIA", &master, &Encounter::WILD).unwrap(),
+      (iv(15), iv(15), iv(15), Level::new(51, false).unwrap(), 3973)
+    );
+    let no_xl = Mechanics::from_gamemaster(&test_fixture())
+      .unwrap()
+      .with_level_cap(Level::new(40, false).unwrap())
+      .unwrap();
+    assert_eq!(
+      max_statproduct(&no_xl, "ALTARIA", &master, &Encounter::WILD).unwrap(),
+      (iv(15), iv(15), iv(15), Level::new(40, false).unwrap(), 3241)
+    );
+
+    let mut capped = League::new("capped", Some(1500));
+    capped.level_cap = Some(Level::new(20, false).unwrap());
+    let (_, _, _, level, _) = max_statproduct(&mech, "ALTARIA", &capped, &Encounter::WILD).unwrap();
+    assert!(level <= Level::new(20, false).unwrap());
+
+    capped.banned.insert(SpeciesId::from("ALTARIA"));
+    assert!(max_statproduct(&mech, "ALTARIA", &capped, &Encounter::WILD).is_err());
+  }
+
+  #[test]
+  fn test_iv_floors() {
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
+    let great = League::new("great", Some(1500));
+
+    let wild = rank_table(&mech, "REGISTEEL", &great, &Encounter::WILD).unwrap();
+    assert_eq!(wild.len(), 4096);
+    assert_eq!((wild[0].atk, wild[0].def, wild[0].sta), (iv(0), iv(8), iv(15)));
+    assert!(wild.iter().all(|e| e.rank == e.overall_rank));
+
+    let lucky = rank_table(&mech, "REGISTEEL", &great, &Encounter::LUCKY_TRADE).unwrap();
+    assert_eq!(lucky.len(), 4 * 4 * 4);
+    assert!(lucky.iter().all(|e| e.atk >= iv(12) && e.def >= iv(12) && e.sta >= iv(12) && e.cp <= 1500));
+    assert_eq!(
+      (lucky[0].atk, lucky[0].def, lucky[0].sta, lucky[0].level, lucky[0].rank, lucky[0].overall_rank),
+      (iv(12), iv(14), iv(13), Level::new(22, false).unwrap(), 1, 1022)
+    );
+    assert_eq!(lucky[63].rank, 64);
+
+    assert_eq!(
+      max_statproduct(&mech, "REGISTEEL", &great, &Encounter::BEST_FRIEND_TRADE).unwrap(),
+      (iv(5), iv(15), iv(13), Level::new(23, false).unwrap(), 2369)
+    );
+
+    // Raid Gengar hatches at level 20, which is already too strong for great league
+    assert!(rank_table(&mech, "GENGAR", &great, &Encounter::RESEARCH).is_ok());
+    assert!(rank_table(&mech, "GENGAR", &great, &Encounter::RAID).is_err());
+
+    let master = League::new("master", None);
+    let raid_no_xl = Encounter::RAID.with_max_level(Level::new(40, false).unwrap());
+    let best = rank_table(&mech, "REGISTEEL", &master, &raid_no_xl).unwrap()[0];
+    assert_eq!((best.atk, best.def, best.sta, best.level), (iv(15), iv(15), iv(15), Level::new(40, false).unwrap()));
+    assert_eq!((best.rank, best.overall_rank), (1, 1));
+  }
+
+  #[test]
+  fn test_rank_forms() {
+    let mech = Mechanics::from_gamemaster(&test_fixture()).unwrap();
+    let great = League::new("great", Some(1500));
+
+    let alolan = rank_table(&mech, ("MAROWAK", "MAROWAK_ALOLA"), &great, &Encounter::WILD).unwrap();
+    assert_eq!(alolan.len(), 4096);
+    assert!(alolan.iter().all(|e| e.cp <= 1500));
+    // Forms are looked up, not replaced by the default one
+    assert!(rank_table(&mech, ("MAROWAK", "GALARIAN"), &great, &Encounter::WILD).is_err());
+    let mut no_alolan = great.clone();
+    no_alolan.banned.insert(SpeciesId::from(("MAROWAK", "ALOLA")));
+    assert!(rank_table(&mech, "MAROWAK", &no_alolan, &Encounter::WILD).is_ok());
+    assert!(rank_table(&mech, ("MAROWAK", "ALOLA"), &no_alolan, &Encounter::WILD).is_err());
+
+    let alola = mech.pokemon(("MAROWAK", "ALOLA")).unwrap();
+    let cpm = mech.cp_multiplier(&alolan[0].level).unwrap();
+    let moves = (alola.fast_moves.values().next().unwrap(), alola.charged_moves.values().next().unwrap());
+    let best = PokemonInstance::new(
+      alola.clone(), alolan[0].level, cpm,
+      alolan[0].atk, alolan[0].def, alolan[0].sta,
+      moves.0.clone(), moves.1.clone(), moves.1.clone(),
+    );
+    assert_eq!(best.cp(), alolan[0].cp);
+    assert_eq!(
+      max_statproduct(&mech, ("MAROWAK", "ALOLA"), &great, &Encounter::WILD).unwrap(),
+      (alolan[0].atk, alolan[0].def, alolan[0].sta, alolan[0].level, alolan[0].stat_product.round() as u32)
+    );
+  }
 }